@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ProjectConfig;
+use crate::store::*;
+use crate::token::{self, AclEntry};
+
+pub fn routes() -> Router<Arc<Store>> {
+    Router::new()
+        .route("/admin/projects", post(create_project))
+        .route("/admin/project/{project}", delete(delete_project))
+        .route("/admin/project/{project}/acl", get(get_acl))
+        .route("/admin/project/{project}/config", put(set_config))
+        .route("/admin/project/{project}/acl/{role}", post(add_acl_entry))
+        .route(
+            "/admin/project/{project}/acl/{role}/revoke",
+            post(revoke_acl_entry),
+        )
+}
+
+#[derive(Deserialize)]
+struct NewProject {
+    name: String,
+    #[serde(flatten)]
+    config: ProjectConfig,
+}
+
+async fn create_project(
+    State(store): State<Arc<Store>>,
+    headers: HeaderMap,
+    Json(new_project): Json<NewProject>,
+) -> Result<StatusCode, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let admin = store.admin(&headers)?;
+            store.create_project(&admin, new_project.name, &new_project.config)
+        })
+        .await?;
+    Ok(StatusCode::CREATED)
+}
+
+async fn delete_project(
+    State(store): State<Arc<Store>>,
+    Path(project): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let admin = store.admin(&headers)?;
+            store.delete_project(&admin, project)
+        })
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_acl(
+    State(store): State<Arc<Store>>,
+    Path(project): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<ProjectAcl>, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let admin = store.admin(&headers)?;
+            store.project_acl(&admin, project)
+        })
+        .await
+        .map(Json)
+}
+
+async fn set_config(
+    State(store): State<Arc<Store>>,
+    Path(project): Path<String>,
+    headers: HeaderMap,
+    Json(config): Json<ProjectConfig>,
+) -> Result<StatusCode, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let admin = store.admin(&headers)?;
+            store.set_project_config(&admin, project, &config)
+        })
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct AclEntryRequest {
+    #[serde(flatten)]
+    entry: AclEntry,
+    #[serde(default)]
+    generate: bool,
+}
+
+#[derive(Serialize)]
+struct AddedAclEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+async fn add_acl_entry(
+    State(store): State<Arc<Store>>,
+    Path((project, role)): Path<(String, AclRole)>,
+    headers: HeaderMap,
+    Json(request): Json<AclEntryRequest>,
+) -> Result<Json<AddedAclEntry>, StoreError> {
+    let mut entry = request.entry;
+    let generated = match request.generate {
+        true if entry.token.is_none() => {
+            let token = token::random_hex(32);
+            entry.token = Some(token.clone());
+            Some(token)
+        }
+        true => return Err(StoreError::InvalidAclEntry),
+        false => None,
+    };
+    store
+        .run_blocking(move |store| {
+            let admin = store.admin(&headers)?;
+            store.add_acl_entry(&admin, project, role, &entry)
+        })
+        .await?;
+    Ok(Json(AddedAclEntry { token: generated }))
+}
+
+#[derive(Serialize)]
+struct RevokedAclEntries {
+    removed: usize,
+}
+
+async fn revoke_acl_entry(
+    State(store): State<Arc<Store>>,
+    Path((project, role)): Path<(String, AclRole)>,
+    headers: HeaderMap,
+    Json(entry): Json<AclEntry>,
+) -> Result<Json<RevokedAclEntries>, StoreError> {
+    let removed = store
+        .run_blocking(move |store| {
+            let admin = store.admin(&headers)?;
+            store.remove_acl_entry(&admin, project, role, &entry)
+        })
+        .await?;
+    Ok(Json(RevokedAclEntries { removed }))
+}
@@ -0,0 +1,70 @@
+use std::io::{self, Read, Write};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::metadata::*;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ArchiveFormat {
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "application/gzip",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+}
+
+// writes every file of a version into an archive, under a directory named `root`, opening
+// each file only when it's its turn
+pub fn write_archive<W: Write>(
+    format: ArchiveFormat,
+    root: &str,
+    metadata: &VersionMetadata,
+    mut open: impl FnMut(&FileMetadata) -> io::Result<Box<dyn Read + Send>>,
+    writer: W,
+) -> io::Result<()> {
+    match format {
+        ArchiveFormat::TarGz => {
+            let mut builder = tar::Builder::new(GzEncoder::new(writer, Compression::default()));
+            for file in &metadata.files {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(file.size);
+                header.set_mode(0o644);
+                header.set_mtime(metadata.uploaded_at);
+                let contents = open(file)?.take(file.size);
+                builder.append_data(&mut header, format!("{}/{}", root, file.name), contents)?;
+            }
+            builder.into_inner()?.finish()?;
+        }
+        ArchiveFormat::Zip => {
+            let mut zip = ZipWriter::new_stream(writer);
+            for file in &metadata.files {
+                let options = SimpleFileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .unix_permissions(0o644)
+                    .large_file(file.size >= u32::MAX as u64);
+                zip.start_file(format!("{}/{}", root, file.name), options)
+                    .map_err(io::Error::other)?;
+                io::copy(&mut open(file)?.take(file.size), &mut zip)?;
+            }
+            zip.finish().map_err(io::Error::other)?;
+        }
+    }
+    Ok(())
+}
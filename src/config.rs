@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+
+use glob::{Pattern, PatternError};
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::VersionMetadata;
+use crate::store::Version;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProjectConfig {
+    #[serde(default)]
+    pub public: bool,
+    #[serde(default)]
+    pub retention: Option<RetentionPolicy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    #[serde(default)]
+    pub keep_last: Option<usize>,
+    #[serde(default)]
+    pub keep_days: Option<u64>,
+    #[serde(default)]
+    pub keep_patterns: Vec<String>,
+    #[serde(default)]
+    pub keep_labels: BTreeMap<String, String>,
+}
+
+impl ProjectConfig {
+    pub fn validate(&self) -> Result<(), PatternError> {
+        match &self.retention {
+            Some(retention) => retention.validate(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn validate(&self) -> Result<(), PatternError> {
+        for pattern in self.keep_patterns.iter().chain(self.keep_labels.values()) {
+            Pattern::new(pattern)?;
+        }
+        Ok(())
+    }
+
+    // versions must be sorted oldest first, as returned by the store
+    pub fn expired<'a>(
+        &self,
+        versions: &'a [(Version, VersionMetadata)],
+        now: u64,
+    ) -> Result<Vec<&'a Version>, PatternError> {
+        // without a limit on count or age there is nothing to expire
+        if self.keep_last.is_none() && self.keep_days.is_none() {
+            return Ok(Vec::new());
+        }
+        let patterns = self
+            .keep_patterns
+            .iter()
+            .map(|p| Pattern::new(p))
+            .collect::<Result<Vec<_>, _>>()?;
+        let labels = self
+            .keep_labels
+            .iter()
+            .map(|(key, value)| Ok((key, Pattern::new(value)?)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut newer = 0;
+        let mut expired = Vec::new();
+        for (version, metadata) in versions.iter().rev() {
+            // yanked versions don't take up one of the last N slots
+            if metadata.yanked.is_none() {
+                newer += 1;
+                if self.keep_last.is_some_and(|n| newer <= n) {
+                    continue;
+                }
+            }
+            if self.keep_days.is_some_and(|days| {
+                now.saturating_sub(metadata.uploaded_at) < days.saturating_mul(86400)
+            }) {
+                continue;
+            }
+            if patterns.iter().any(|p| p.matches(version.name())) {
+                continue;
+            }
+            if labels.iter().any(|(key, pattern)| {
+                metadata
+                    .labels
+                    .get(*key)
+                    .is_some_and(|value| pattern.matches(value))
+            }) {
+                continue;
+            }
+            expired.push(version);
+        }
+        expired.reverse();
+        Ok(expired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::Yank;
+
+    const DAY: u64 = 86400;
+    const NOW: u64 = 100 * DAY;
+
+    fn version(name: &str, age_days: u64) -> (Version, VersionMetadata) {
+        let metadata = VersionMetadata {
+            uploaded_at: NOW - age_days * DAY,
+            ..Default::default()
+        };
+        (Version::new(name.to_owned()).unwrap(), metadata)
+    }
+
+    fn yanked(name: &str, age_days: u64) -> (Version, VersionMetadata) {
+        let (version, mut metadata) = version(name, age_days);
+        metadata.yanked = Some(Yank {
+            yanked_at: NOW,
+            yanked_by: None,
+            reason: None,
+        });
+        (version, metadata)
+    }
+
+    fn expired(policy: &RetentionPolicy, versions: &[(Version, VersionMetadata)]) -> Vec<String> {
+        policy
+            .expired(versions, NOW)
+            .unwrap()
+            .into_iter()
+            .map(|v| v.name().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn nothing_expires_without_a_limit() {
+        let policy = RetentionPolicy {
+            keep_patterns: vec!["1.*".to_owned()],
+            ..Default::default()
+        };
+        let versions = [version("0.1", 50), version("0.2", 40)];
+        assert!(expired(&policy, &versions).is_empty());
+    }
+
+    #[test]
+    fn keep_last() {
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        let versions = [
+            version("0.1", 4),
+            version("0.2", 3),
+            version("0.3", 2),
+            version("0.4", 1),
+        ];
+        assert_eq!(expired(&policy, &versions), ["0.1", "0.2"]);
+    }
+
+    #[test]
+    fn keep_days() {
+        let policy = RetentionPolicy {
+            keep_days: Some(10),
+            ..Default::default()
+        };
+        let versions = [version("0.1", 30), version("0.2", 10), version("0.3", 9)];
+        assert_eq!(expired(&policy, &versions), ["0.1", "0.2"]);
+    }
+
+    #[test]
+    fn keep_days_does_not_overflow() {
+        let policy = RetentionPolicy {
+            keep_days: Some(u64::MAX),
+            ..Default::default()
+        };
+        let versions = [version("0.1", 99)];
+        assert!(expired(&policy, &versions).is_empty());
+    }
+
+    #[test]
+    fn keep_last_and_keep_days_both_protect() {
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            keep_days: Some(10),
+            ..Default::default()
+        };
+        let versions = [version("0.1", 30), version("0.2", 5), version("0.3", 20)];
+        assert_eq!(expired(&policy, &versions), ["0.1"]);
+    }
+
+    #[test]
+    fn patterns_and_labels_protect() {
+        let policy = RetentionPolicy {
+            keep_last: Some(0),
+            keep_patterns: vec!["1.*".to_owned()],
+            keep_labels: BTreeMap::from([("channel".to_owned(), "stable*".to_owned())]),
+            ..Default::default()
+        };
+        let mut labelled = version("0.2", 2);
+        labelled
+            .1
+            .labels
+            .insert("channel".to_owned(), "stable-2".to_owned());
+        let mut mislabelled = version("0.3", 1);
+        mislabelled
+            .1
+            .labels
+            .insert("track".to_owned(), "stable".to_owned());
+        let versions = [version("0.1", 3), labelled, mislabelled, version("1.0", 1)];
+        assert_eq!(expired(&policy, &versions), ["0.1", "0.3"]);
+    }
+
+    #[test]
+    fn yanked_versions_do_not_count_toward_keep_last() {
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        let versions = [
+            version("0.1", 4),
+            version("0.2", 3),
+            yanked("0.3", 2),
+            version("0.4", 1),
+        ];
+        assert_eq!(expired(&policy, &versions), ["0.1", "0.3"]);
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            keep_patterns: vec!["[".to_owned()],
+            ..Default::default()
+        };
+        assert!(policy.validate().is_err());
+        assert!(policy.expired(&[], NOW).is_err());
+    }
+}
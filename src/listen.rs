@@ -0,0 +1,279 @@
+use std::fmt::{self, Debug};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use axum::serve::Listener;
+use axum::Router;
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{event, Level};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err(format!("invalid listen address {}", s)),
+            Some(path) => Ok(ListenAddr::Unix(path.into())),
+            None => s
+                .parse()
+                .map(ListenAddr::Tcp)
+                .map_err(|_| format!("invalid listen address {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CertificateResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertificateResolver {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> io::Result<Self> {
+        let current = RwLock::new(Arc::new(load_certified_key(&cert_path, &key_path)?));
+        Ok(CertificateResolver {
+            cert_path,
+            key_path,
+            current,
+        })
+    }
+
+    pub fn reload(&self) -> io::Result<()> {
+        let key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(cert_path: &PathBuf, key_path: &PathBuf) -> io::Result<CertifiedKey> {
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(format!("{}: {}", cert_path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(invalid(format!(
+            "{}: no certificates found",
+            cert_path.display()
+        )));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| invalid(format!("{}: {}", key_path.display(), e)))?;
+    let signing_key = ring::default_provider()
+        .key_provider
+        .load_private_key(key)
+        .map_err(|e| invalid(format!("{}: {}", key_path.display(), e)))?;
+    let certified_key = CertifiedKey::new(certs, signing_key);
+    certified_key
+        .keys_match()
+        .map_err(|e| invalid(format!("{}: {}", key_path.display(), e)))?;
+    Ok(certified_key)
+}
+
+pub fn tls_acceptor(resolver: Arc<CertificateResolver>) -> TlsAcceptor {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    TlsAcceptor::from(Arc::new(config))
+}
+
+pub fn reload_on_sighup(resolver: Arc<CertificateResolver>) -> io::Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match resolver.reload() {
+                Ok(()) => event!(Level::INFO, "reloaded TLS certificate"),
+                Err(e) => event!(Level::ERROR, "failed to reload TLS certificate: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+pub struct TlsListener<L: Listener> {
+    inner: L,
+    acceptor: TlsAcceptor,
+    sender: mpsc::Sender<(TlsStream<L::Io>, L::Addr)>,
+    connections: mpsc::Receiver<(TlsStream<L::Io>, L::Addr)>,
+}
+
+impl<L: Listener> TlsListener<L> {
+    pub fn new(inner: L, acceptor: TlsAcceptor) -> Self {
+        let (sender, connections) = mpsc::channel(64);
+        TlsListener {
+            inner,
+            acceptor,
+            sender,
+            connections,
+        }
+    }
+}
+
+impl<L> Listener for TlsListener<L>
+where
+    L: Listener,
+    L::Addr: Debug,
+{
+    type Io = TlsStream<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        // handshakes run in their own tasks so a slow client can't stall the others
+        loop {
+            tokio::select! {
+                Some(connection) = self.connections.recv() => return connection,
+                (io, addr) = self.inner.accept() => {
+                    let acceptor = self.acceptor.clone();
+                    let sender = self.sender.clone();
+                    tokio::spawn(async move {
+                        match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(io)).await {
+                            Ok(Ok(stream)) => {
+                                let _ = sender.send((stream, addr)).await;
+                            }
+                            Ok(Err(e)) => {
+                                event!(Level::DEBUG, "TLS handshake with {:?} failed: {}", addr, e)
+                            }
+                            Err(_) => {
+                                event!(Level::DEBUG, "TLS handshake with {:?} timed out", addr)
+                            }
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.inner.local_addr()
+    }
+}
+
+async fn bind_unix(path: &PathBuf) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        _ => {}
+    }
+    UnixListener::bind(path)
+}
+
+fn spawn_server<L>(
+    servers: &mut JoinSet<io::Result<()>>,
+    listener: L,
+    app: Router,
+    tls: Option<&TlsAcceptor>,
+) where
+    L: Listener,
+    L::Addr: Debug,
+{
+    match tls {
+        Some(acceptor) => {
+            let listener = TlsListener::new(listener, acceptor.clone());
+            servers.spawn(async move { axum::serve(listener, app).await });
+        }
+        None => {
+            servers.spawn(async move { axum::serve(listener, app).await });
+        }
+    }
+}
+
+pub async fn serve(addrs: &[ListenAddr], app: Router, tls: Option<TlsAcceptor>) -> io::Result<()> {
+    let mut servers = JoinSet::new();
+    for addr in addrs {
+        let bind_error = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", addr, e));
+        match addr {
+            ListenAddr::Tcp(socket_addr) => {
+                let listener = TcpListener::bind(socket_addr).await.map_err(bind_error)?;
+                spawn_server(&mut servers, listener, app.clone(), tls.as_ref());
+            }
+            ListenAddr::Unix(path) => {
+                let listener = bind_unix(path).await.map_err(bind_error)?;
+                spawn_server(&mut servers, listener, app.clone(), tls.as_ref());
+            }
+        }
+        event!(
+            Level::INFO,
+            "listening on {}{}",
+            addr,
+            if tls.is_some() { " with TLS" } else { "" }
+        );
+    }
+    while let Some(result) = servers.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listen_addrs() {
+        for (s, expected) in [
+            ("127.0.0.1:3000", "127.0.0.1:3000"),
+            ("[::1]:3000", "[::1]:3000"),
+            ("[::]:443", "[::]:443"),
+            ("unix:/run/artifacts.sock", "unix:/run/artifacts.sock"),
+        ] {
+            assert_eq!(s.parse::<ListenAddr>().unwrap().to_string(), expected);
+        }
+        assert!(matches!(
+            "unix:relative.sock".parse(),
+            Ok(ListenAddr::Unix(path)) if path.as_os_str() == "relative.sock"
+        ));
+        for s in [
+            "",
+            "localhost:3000",
+            "127.0.0.1",
+            "::1:3000",
+            "3000",
+            "unix",
+            "unix:",
+        ] {
+            assert!(s.parse::<ListenAddr>().is_err(), "{}", s);
+        }
+    }
+}
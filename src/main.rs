@@ -1,98 +1,989 @@
+mod admin;
+mod archive;
+mod config;
+mod listen;
+mod metadata;
+mod s3;
+mod storage;
 mod store;
+#[cfg(test)]
+mod tests;
+mod token;
+mod upload;
 
+use archive::ArchiveFormat;
+use futures_util::stream;
+use listen::*;
+use metadata::{
+    Channel, DownloadRequest, FileMetadata, MoveChannelRequest, ProjectInfo, PromoteRequest,
+    Promoted, VersionMetadata, YankRequest,
+};
+use s3::S3;
+use storage::{Filesystem, Object, Storage};
 use store::*;
+use token::{Scope, TokenRecord};
 use tower_http::services::ServeFile;
+use upload::Upload;
 
-use std::{collections::HashMap, fs, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read, Write},
+    path::PathBuf,
+    process,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
 use axum::{
-    extract::{Multipart, Path, Query, State},
-    http::HeaderMap,
-    response::{IntoResponse, Redirect, Result},
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, Multipart, Path, Query, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Redirect, Response},
     routing::{get, post},
     Json, Router,
 };
-use clap::Parser;
-use tracing::{event, instrument, Level};
+use clap::{Parser, Subcommand};
+use tracing::{event, Level};
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about=None)]
+#[command(version, about, long_about=None, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[arg(long, required = true)]
+    state_dir: Option<String>,
+
+    #[command(flatten)]
+    storage: StorageArgs,
+
+    /// Maximum total size of an upload, e.g. 500M or 4G
+    #[arg(long, value_parser = parse_size)]
+    max_upload_size: Option<u64>,
+
+    /// Address to listen on, e.g. 0.0.0.0:3000, [::]:3000 or unix:/run/artifacts.sock; may be repeated
+    #[arg(long, default_value = "0.0.0.0:3000")]
+    listen: Vec<ListenAddr>,
+
+    /// PEM certificate chain to serve TLS with; reloaded on SIGHUP
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert; reloaded on SIGHUP
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// How often to delete versions expired by project retention policies, e.g. 30m or 1d; 0 disables
+    #[arg(long, value_parser = parse_duration, default_value = "1h")]
+    gc_interval: Duration,
+
+    /// Only log the versions that retention policies would delete
     #[arg(long)]
-    state_dir: String,
+    gc_dry_run: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct StorageArgs {
+    /// Keep projects in this S3-compatible bucket rather than the state directory, which is then
+    /// only used for staging uploads; credentials are read from AWS_ACCESS_KEY_ID and
+    /// AWS_SECRET_ACCESS_KEY
+    #[arg(long, requires = "s3_endpoint")]
+    s3_bucket: Option<String>,
+
+    /// e.g. https://s3.eu-west-1.amazonaws.com or http://localhost:9000
+    #[arg(long, requires = "s3_bucket")]
+    s3_endpoint: Option<String>,
+
+    #[arg(long, default_value = "us-east-1")]
+    s3_region: String,
+
+    /// Key prefix to keep everything under within the bucket
+    #[arg(long, default_value = "")]
+    s3_prefix: String,
+}
+
+impl StorageArgs {
+    fn store(self, state_dir: String, max_upload_size: Option<u64>) -> Result<Store, String> {
+        let storage: Box<dyn Storage> = match (self.s3_bucket, self.s3_endpoint) {
+            (Some(bucket), Some(endpoint)) => {
+                let credential = |name| {
+                    std::env::var(name).map_err(|_| format!("{} must be set to use S3", name))
+                };
+                Box::new(S3::new(
+                    endpoint,
+                    bucket,
+                    self.s3_prefix,
+                    self.s3_region,
+                    credential("AWS_ACCESS_KEY_ID")?,
+                    credential("AWS_SECRET_ACCESS_KEY")?,
+                ))
+            }
+            _ => Box::new(Filesystem::new(PathBuf::from(&state_dir))),
+        };
+        Ok(Store::new(state_dir, storage, max_upload_size))
+    }
+}
+
+fn open_store(storage: StorageArgs, state_dir: String, max_upload_size: Option<u64>) -> Store {
+    storage
+        .store(state_dir, max_upload_size)
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        })
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Read a token from stdin and print the hashed line to put in readers.txt or writers.txt
+    HashToken,
+    /// Replace plaintext tokens in every project's readers.txt and writers.txt with hashes
+    MigrateTokens {
+        #[arg(long)]
+        state_dir: String,
+        #[command(flatten)]
+        storage: StorageArgs,
+    },
+    /// Generate a new named token, record it in the token registry and print it
+    NewToken {
+        #[arg(long)]
+        state_dir: String,
+        #[command(flatten)]
+        storage: StorageArgs,
+        #[arg(long)]
+        name: String,
+        #[arg(long)]
+        owner: Option<String>,
+        /// Comma-separated scopes out of read, write, delete and admin
+        #[arg(long, value_delimiter = ',', default_value = "read,write")]
+        scopes: Vec<Scope>,
+        #[arg(long)]
+        expires_in_days: Option<u64>,
+    },
+    /// Delete every version expired by its project's retention policy
+    Gc {
+        #[arg(long)]
+        state_dir: String,
+        #[command(flatten)]
+        storage: StorageArgs,
+        /// Print the versions that would be deleted without deleting them
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        Some((i, 'T' | 't')) => (&s[..i], 1 << 40),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size {}", s))
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 60 * 60),
+        Some((i, 'd')) => (&s[..i], 24 * 60 * 60),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("invalid duration {}", s))
 }
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    let shared_state = Arc::new(Store::new(args.state_dir));
+    match args.command {
+        Some(Command::HashToken) => {
+            let mut token = String::new();
+            io::stdin().read_line(&mut token).unwrap();
+            println!("{}", token::hash_token(token.trim()));
+            return;
+        }
+        Some(Command::MigrateTokens { state_dir, storage }) => {
+            match open_store(storage, state_dir, None).migrate_tokens() {
+                Ok(migrated) => println!("hashed {} plaintext tokens", migrated),
+                Err(e) => {
+                    eprintln!("failed to migrate tokens: {}", e);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::NewToken {
+            state_dir,
+            storage,
+            name,
+            owner,
+            scopes,
+            expires_in_days,
+        }) => {
+            let token = token::random_hex(32);
+            let record = TokenRecord {
+                name,
+                owner,
+                hash: token::hash_token(&token),
+                expires_at: expires_in_days.map(|days| {
+                    metadata::unix_time(SystemTime::now())
+                        .saturating_add(days.saturating_mul(86400))
+                }),
+                scopes,
+            };
+            match open_store(storage, state_dir, None).add_token(record) {
+                Ok(()) => println!("{}", token),
+                Err(e) => {
+                    eprintln!("failed to add token: {}", e);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Gc {
+            state_dir,
+            storage,
+            dry_run,
+        }) => {
+            tracing_subscriber::fmt().with_writer(io::stderr).init();
+            match open_store(storage, state_dir, None).collect_garbage(dry_run) {
+                Ok(collected) => {
+                    for (project, version) in collected {
+                        match dry_run {
+                            true => {
+                                println!("would delete version {} of project {}", version, project)
+                            }
+                            false => println!("deleted version {} of project {}", version, project),
+                        }
+                    }
+                }
+                Err(e) => {
+                    eprintln!("failed to collect garbage: {}", e);
+                    process::exit(1);
+                }
+            }
+            return;
+        }
+        None => {}
+    }
+    let shared_state = Arc::new(open_store(
+        args.storage,
+        args.state_dir.unwrap(),
+        args.max_upload_size,
+    ));
 
     tracing_subscriber::fmt::init();
-    let app = Router::new()
+    match shared_state.sweep_staging() {
+        Ok(0) => {}
+        Ok(n) => event!(Level::INFO, "removed {} abandoned staging directories", n),
+        Err(e) => event!(Level::WARN, "failed to sweep staging directories: {}", e),
+    }
+    if !args.gc_interval.is_zero() {
+        tokio::spawn(collect_garbage(
+            shared_state.clone(),
+            args.gc_interval,
+            args.gc_dry_run,
+        ));
+    }
+    let app = router(shared_state);
+
+    let tls = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let resolver = CertificateResolver::new(cert, key)
+                .map(Arc::new)
+                .unwrap_or_else(|e| {
+                    eprintln!("failed to load TLS certificate: {}", e);
+                    process::exit(1);
+                });
+            if let Err(e) = reload_on_sighup(resolver.clone()) {
+                eprintln!("failed to listen for SIGHUP: {}", e);
+                process::exit(1);
+            }
+            Some(tls_acceptor(resolver))
+        }
+        _ => None,
+    };
+    if let Err(e) = listen::serve(&args.listen, app, tls).await {
+        eprintln!("failed to listen on {}", e);
+        process::exit(1);
+    }
+}
+
+async fn collect_garbage(store: Arc<Store>, interval: Duration, dry_run: bool) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let store = store.clone();
+        match tokio::task::spawn_blocking(move || store.collect_garbage(dry_run)).await {
+            Ok(Ok(collected)) if dry_run => {
+                for (project, version) in collected {
+                    event!(
+                        Level::INFO,
+                        "retention policy would delete version {} of project {}",
+                        version,
+                        project
+                    );
+                }
+            }
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => event!(Level::WARN, "failed to collect garbage: {}", e),
+            Err(e) => event!(Level::ERROR, "garbage collection panicked: {}", e),
+        }
+    }
+}
+
+fn router(store: Arc<Store>) -> Router {
+    Router::new()
         .route("/projects", get(get_projects))
         .route("/project/{project}/versions", get(get_versions))
+        .route(
+            "/project/{project}/version/{version}",
+            get(get_version_metadata).delete(delete_version),
+        )
+        .route(
+            "/project/{project}/version/{version}/yank",
+            post(yank_version).delete(unyank_version),
+        )
         .route(
             "/project/{project}/version/{version}/download",
             get(get_version),
         )
+        .route(
+            "/project/{project}/version/{version}/archive.tar.gz",
+            get(get_version_tar_gz),
+        )
+        .route(
+            "/project/{project}/version/{version}/archive.zip",
+            get(get_version_zip),
+        )
+        .route(
+            "/project/{project}/version/{version}/files",
+            get(get_version_files),
+        )
         .route(
             "/project/{project}/version/{version}/file/{file}",
             get(get_version_content),
         )
-        .route("/project/{project}/upload", post(new_version))
-        .with_state(shared_state);
-
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+        .route("/project/{project}/promote", post(promote_version))
+        .route("/project/{project}/channels", get(get_channels))
+        .route(
+            "/project/{project}/channel/{channel}",
+            get(get_channel).put(move_channel),
+        )
+        .route(
+            "/project/{project}/channel/{channel}/download",
+            get(get_channel_download),
+        )
+        .route(
+            "/project/{project}/upload",
+            post(new_version).layer(DefaultBodyLimit::disable()),
+        )
+        .merge(admin::routes())
+        .with_state(store)
 }
 
-async fn get_projects(State(store): State<Arc<Store>>) -> Result<Json<Vec<String>>> {
-    Ok(Json(store.list_projects()?))
+async fn get_projects(
+    State(store): State<Arc<Store>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ProjectInfo>>, StoreError> {
+    store
+        .run_blocking(move |store| store.list_readable_projects(&headers))
+        .await
+        .map(Json)
 }
 
 async fn get_versions(
     State(store): State<Arc<Store>>,
     Path(project): Path<String>,
     headers: HeaderMap,
-) -> Result<Json<Vec<String>>> {
-    let project = store.project_reader(project, &headers)?;
-    Ok(Json(store.list_versions(&project)?))
+) -> Result<Json<Vec<String>>, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let project = store.project_reader(project, &headers)?;
+            store.list_versions(&project)
+        })
+        .await
+        .map(Json)
 }
 
 async fn get_version(
     State(store): State<Arc<Store>>,
     Path((project, version)): Path<(String, String)>,
+    Query(request): Query<DownloadRequest>,
+    headers: HeaderMap,
+    req: axum::extract::Request,
+) -> Result<Response, StoreError> {
+    download(
+        store,
+        project,
+        Target::Version(version),
+        request,
+        headers,
+        req,
+    )
+    .await
+}
+
+enum Target {
+    Version(String),
+    Channel(String),
+}
+
+// redirects to the chosen file of a version, or with direct serves it right away
+async fn download(
+    store: Arc<Store>,
+    project: String,
+    target: Target,
+    request: DownloadRequest,
+    headers: HeaderMap,
+    req: axum::extract::Request,
+) -> Result<Response, StoreError> {
+    let (location, served, alias) = store
+        .run_blocking(move |store| {
+            let project = store.project_reader(project, &headers)?;
+            let (version, alias) = match target {
+                Target::Version(name) => {
+                    let version = store.resolve_version(&project, name.clone())?;
+                    let alias = version.name() != name;
+                    (version, alias)
+                }
+                Target::Channel(name) => (store.resolve_channel(&project, &name)?, true),
+            };
+            let file = store.file_for_version(&project, &version, request.file.as_deref())?;
+            let location = format!(
+                "/project/{}/version/{}/file/{}",
+                project.name(),
+                version.name(),
+                file
+            );
+            if !request.direct {
+                return Ok((location, None, alias));
+            }
+            let metadata = store.version_metadata(&project, &version)?;
+            let range = forwarded_range(&headers, metadata.file(&file));
+            let object = store.open_file(&project, &version, &file, range)?;
+            event!(
+                Level::INFO,
+                "{} downloaded {} from version {} of project {}",
+                project.identity().name(),
+                file,
+                version.name(),
+                project.name()
+            );
+            let file = metadata.file(&file).cloned();
+            Ok((location, Some((object, file)), alias))
+        })
+        .await?;
+    match served {
+        None => Ok(Redirect::to(&location).into_response()),
+        Some((object, file)) => {
+            serve_file(object, &file.ok_or(StoreError::FileNotFound)?, alias, req).await
+        }
+    }
+}
+
+async fn get_version_metadata(
+    State(store): State<Arc<Store>>,
+    Path((project, version)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Json<VersionMetadata>, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let project = store.project_reader(project, &headers)?;
+            let version = store.resolve_version(&project, version)?;
+            store.version_metadata(&project, &version)
+        })
+        .await
+        .map(Json)
+}
+
+async fn delete_version(
+    State(store): State<Arc<Store>>,
+    Path((project, version)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<StatusCode, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let project = store.project_deleter(project, &headers)?;
+            let version = Version::new(version)?;
+            store.delete_version(&project, &version)
+        })
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn yank_version(
+    State(store): State<Arc<Store>>,
+    Path((project, version)): Path<(String, String)>,
+    Query(request): Query<YankRequest>,
+    headers: HeaderMap,
+) -> Result<Json<VersionMetadata>, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let project = store.project_writer(project, &headers)?;
+            let version = Version::new(version)?;
+            store.yank_version(&project, &version, request.reason)
+        })
+        .await
+        .map(Json)
+}
+
+async fn unyank_version(
+    State(store): State<Arc<Store>>,
+    Path((project, version)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Json<VersionMetadata>, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let project = store.project_writer(project, &headers)?;
+            let version = Version::new(version)?;
+            store.unyank_version(&project, &version)
+        })
+        .await
+        .map(Json)
+}
+
+async fn get_version_files(
+    State(store): State<Arc<Store>>,
+    Path((project, version)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<String>>, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let project = store.project_reader(project, &headers)?;
+            let version = store.resolve_version(&project, version)?;
+            store.list_files(&project, &version)
+        })
+        .await
+        .map(Json)
+}
+
+async fn promote_version(
+    State(store): State<Arc<Store>>,
+    Path(project): Path<String>,
+    headers: HeaderMap,
+    Json(request): Json<PromoteRequest>,
+) -> Result<Json<Promoted>, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let destination = store.project_writer(project, &headers)?;
+            if !request.move_version {
+                let source = store.project_reader(request.from, &headers)?;
+                let version = store.resolve_version(&source, request.version)?;
+                let metadata = store.promote_version(&source, &version, &destination)?;
+                return Ok(Promoted {
+                    metadata,
+                    warning: None,
+                });
+            }
+            let source = store.project_deleter(request.from, &headers)?;
+            let version = store.resolve_version(source.writer().reader(), request.version)?;
+            store.check_deletable(&source, &version)?;
+            let metadata =
+                store.promote_version(source.writer().reader(), &version, &destination)?;
+            // the promoted version is already published, so failing now would only make the
+            // client retry into version_exists
+            let warning = match store.delete_version(&source, &version) {
+                Ok(()) => None,
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "promoted version {} to project {} but failed to delete it from project {}: {}",
+                        version.name(),
+                        destination.name(),
+                        source.name(),
+                        e
+                    );
+                    Some(format!(
+                        "version was promoted but not deleted from project {}: {}",
+                        source.name(),
+                        e
+                    ))
+                }
+            };
+            Ok(Promoted { metadata, warning })
+        })
+        .await
+        .map(Json)
+}
+
+async fn get_channels(
+    State(store): State<Arc<Store>>,
+    Path(project): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<BTreeMap<String, String>>, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let project = store.project_reader(project, &headers)?;
+            store.list_channels(&project)
+        })
+        .await
+        .map(Json)
+}
+
+async fn get_channel(
+    State(store): State<Arc<Store>>,
+    Path((project, channel)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Json<Channel>, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let project = store.project_reader(project, &headers)?;
+            store.channel(&project, &channel)
+        })
+        .await
+        .map(Json)
+}
+
+async fn move_channel(
+    State(store): State<Arc<Store>>,
+    Path((project, channel)): Path<(String, String)>,
+    headers: HeaderMap,
+    Json(request): Json<MoveChannelRequest>,
+) -> Result<Json<Channel>, StoreError> {
+    store
+        .run_blocking(move |store| {
+            let project = store.project_writer(project, &headers)?;
+            let version = store.resolve_version(project.reader(), request.version)?;
+            store.move_channel(&project, &channel, &version)
+        })
+        .await
+        .map(Json)
+}
+
+async fn get_channel_download(
+    State(store): State<Arc<Store>>,
+    Path((project, channel)): Path<(String, String)>,
+    Query(request): Query<DownloadRequest>,
+    headers: HeaderMap,
+    req: axum::extract::Request,
+) -> Result<Response, StoreError> {
+    download(
+        store,
+        project,
+        Target::Channel(channel),
+        request,
+        headers,
+        req,
+    )
+    .await
+}
+
+async fn get_version_tar_gz(
+    State(store): State<Arc<Store>>,
+    Path((project, version)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, StoreError> {
+    download_archive(store, project, version, headers, ArchiveFormat::TarGz).await
+}
+
+async fn get_version_zip(
+    State(store): State<Arc<Store>>,
+    Path((project, version)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, StoreError> {
+    download_archive(store, project, version, headers, ArchiveFormat::Zip).await
+}
+
+// the archive is built while it's being sent, so it never has to fit in memory or on disk
+async fn download_archive(
+    store: Arc<Store>,
+    project: String,
+    version: String,
     headers: HeaderMap,
-) -> Result<impl IntoResponse> {
-    let project = store.project_reader(project, &headers)?;
-    let version = Version::new(version)?;
-    let file = store.file_for_version(&project, &version)?;
-    Ok(Redirect::to(&format!(
-        "/project/{}/version/{}/file/{}",
-        &project.name(),
-        &version.name(),
-        file
-    )))
+    format: ArchiveFormat,
+) -> Result<Response, StoreError> {
+    let (project, version, metadata, alias) = store
+        .run_blocking(move |store| {
+            let project = store.project_reader(project, &headers)?;
+            let requested = version.clone();
+            let version = store.resolve_version(&project, version)?;
+            let metadata = store.version_metadata(&project, &version)?;
+            event!(
+                Level::INFO,
+                "{} downloaded version {} of project {} as {}",
+                project.identity().name(),
+                version.name(),
+                project.name(),
+                format.extension()
+            );
+            let alias = requested != version.name();
+            Ok((project, version, metadata, alias))
+        })
+        .await?;
+    let root = format!("{}-{}", project.name(), version.name());
+    let file_name = format!("{}.{}", root, format.extension());
+    let mut response = Response::new(blocking_body(move |writer| {
+        let open = |file: &FileMetadata| match store.open_file(&project, &version, &file.name, None)
+        {
+            Ok(object) => object.into_reader(),
+            Err(StoreError::IO(e)) => Err(e),
+            Err(e) => Err(io::Error::other(e.to_string())),
+        };
+        archive::write_archive(format, &root, &metadata, open, writer)
+    }));
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    headers.insert(header::CACHE_CONTROL, cache_control(alias));
+    if let Some(disposition) = content_disposition(&file_name) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok(response)
 }
 
 async fn get_version_content(
     State(store): State<Arc<Store>>,
-    Path((project, version, given_file)): Path<(String, String, String)>,
+    Path((project, version, file)): Path<(String, String, String)>,
     headers: HeaderMap,
     req: axum::extract::Request,
-) -> Result<impl IntoResponse> {
-    let project = store.project_reader(project, &headers)?;
-    let version = Version::new(version)?;
-    let file = store.file_for_version(&project, &version)?;
-    if file != given_file {
-        return Err(StoreError::InvalidFile.into());
-    }
-    let path = store.path_for_version(&project, &version)?;
-    ServeFile::new(&path)
-        .try_call(req)
-        .await
-        .map_err(StoreError::IO)
-        .map_err(|e| e.into())
+) -> Result<Response, StoreError> {
+    let (object, metadata, alias) = store
+        .run_blocking(move |store| {
+            let project = store.project_reader(project, &headers)?;
+            let requested = version.clone();
+            let version = store.resolve_version(&project, version)?;
+            let metadata = store.version_metadata(&project, &version)?;
+            let range = forwarded_range(&headers, metadata.file(&file));
+            let object = store.open_file(&project, &version, &file, range)?;
+            event!(
+                Level::INFO,
+                "{} downloaded {} from version {} of project {}",
+                project.identity().name(),
+                file,
+                version.name(),
+                project.name()
+            );
+            let metadata = metadata.file(&file).cloned();
+            Ok((object, metadata, requested != version.name()))
+        })
+        .await?;
+    serve_file(
+        object,
+        &metadata.ok_or(StoreError::FileNotFound)?,
+        alias,
+        req,
+    )
+    .await
+}
+
+// versions never change once uploaded, but aliases like latest move with every upload
+fn cache_control(alias: bool) -> HeaderValue {
+    HeaderValue::from_static(match alias {
+        true => "max-age=60",
+        false => "max-age=31536000, immutable",
+    })
+}
+
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+fn content_disposition(file_name: &str) -> Option<HeaderValue> {
+    let fallback = file_name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    let mut value = format!("attachment; filename=\"{}\"", fallback);
+    if fallback != file_name {
+        value.push_str("; filename*=UTF-8''");
+        for b in file_name.bytes() {
+            match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    value.push(b as char)
+                }
+                _ => value.push_str(&format!("%{:02X}", b)),
+            }
+        }
+    }
+    HeaderValue::from_str(&value).ok()
+}
+
+async fn serve_file(
+    object: Object,
+    file: &FileMetadata,
+    alias: bool,
+    mut req: axum::extract::Request,
+) -> Result<Response, StoreError> {
+    let mut validators = HeaderMap::new();
+    validators.insert(header::CACHE_CONTROL, cache_control(alias));
+    if let Ok(etag) = HeaderValue::from_str(&file.etag()) {
+        validators.insert(header::ETAG, etag);
+    }
+    if etag_matches(req.headers(), &file.etag()) {
+        let mut response = StatusCode::NOT_MODIFIED.into_response();
+        response.headers_mut().extend(validators);
+        return Ok(response);
+    }
+    // the ETag was already checked, and takes precedence over the modification time
+    if req.headers().contains_key(header::IF_NONE_MATCH) {
+        req.headers_mut().remove(header::IF_MODIFIED_SINCE);
+    }
+    let mut response = match object {
+        Object::Local(path) => ServeFile::new(&path)
+            .try_call(req)
+            .await
+            .map_err(StoreError::IO)?
+            .map(Body::new),
+        Object::Remote(reader) => remote_response(reader, file.size),
+        Object::Partial {
+            reader,
+            content_range,
+            size,
+        } => {
+            let mut response = remote_response(reader, size);
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            if let Ok(content_range) = HeaderValue::from_str(&content_range) {
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_RANGE, content_range);
+            }
+            response
+        }
+        Object::RangeNotSatisfiable => {
+            let mut response = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+            let content_range = format!("bytes */{}", file.size);
+            if let Ok(content_range) = HeaderValue::from_str(&content_range) {
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_RANGE, content_range);
+            }
+            return Ok(response);
+        }
+    };
+    // errors like a 416 for a bad range mustn't be cached in place of the file
+    let status = response.status();
+    if !matches!(
+        status,
+        StatusCode::OK | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED
+    ) {
+        return Ok(response);
+    }
+    let headers = response.headers_mut();
+    headers.extend(validators);
+    if status == StatusCode::NOT_MODIFIED {
+        return Ok(response);
+    }
+    // blobs are named by their hash, so the type can't be guessed from the path
+    if let Some(content_type) = file
+        .content_type
+        .as_deref()
+        .and_then(|t| HeaderValue::from_str(t).ok())
+    {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    if let Some(disposition) = content_disposition(&file.name) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    if let Ok(digest) = HeaderValue::from_str(&file.digest()) {
+        headers.insert(HeaderName::from_static("digest"), digest);
+    }
+    Ok(response)
+}
+
+fn remote_response(reader: Box<dyn Read + Send>, size: u64) -> Response {
+    let mut response = Response::new(reader_body(reader));
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    response
+}
+
+// the Range header to hand on to storage, unless If-Range says the client has a different
+// file, in which case the whole file is sent
+fn forwarded_range<'a>(headers: &'a HeaderMap, file: Option<&FileMetadata>) -> Option<&'a str> {
+    let range = headers.get(header::RANGE)?.to_str().ok()?;
+    match headers.get(header::IF_RANGE) {
+        Some(if_range) if file.is_none_or(|file| *if_range != file.etag().as_str()) => None,
+        _ => Some(range),
+    }
+}
+
+fn reader_body(mut reader: Box<dyn Read + Send>) -> Body {
+    blocking_body(move |writer| io::copy(&mut reader, writer).map(|_| ()))
+}
+
+// streams whatever `write` writes from a blocking thread
+fn blocking_body(
+    write: impl FnOnce(&mut ChannelWriter) -> io::Result<()> + Send + 'static,
+) -> Body {
+    let (sender, receiver) = tokio::sync::mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChannelWriter {
+            sender,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        };
+        if let Err(e) = write(&mut writer).and_then(|()| writer.flush()) {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                event!(Level::WARN, "failed to stream download: {}", e);
+            }
+            // the error aborts the response, so a truncated body isn't mistaken for a whole one
+            let _ = writer.sender.blocking_send(Err(e));
+        }
+    });
+    Body::from_stream(stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    }))
+}
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+struct ChannelWriter {
+    sender: tokio::sync::mpsc::Sender<io::Result<Bytes>>,
+    buffer: Vec<u8>,
+}
+
+impl ChannelWriter {
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        self.sender
+            .blocking_send(Ok(Bytes::from(chunk)))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.send()?;
+        }
+        Ok(())
+    }
 }
 
 async fn new_version(
@@ -101,36 +992,46 @@ async fn new_version(
     Query(params): Query<HashMap<String, String>>,
     headers: HeaderMap,
     mut multipart: Multipart,
-) -> Result<impl IntoResponse> {
-    let project = store.project_writer(project, &headers)?;
+) -> Result<impl IntoResponse, StoreError> {
+    let project = store
+        .run_blocking(move |store| store.project_writer(project, &headers))
+        .await?;
     let version = match params.get("version") {
         Some(v) => Version::new(v.clone()),
-        None => Err(StoreError::Other("did not provide version".to_string())),
+        None => Err(StoreError::MissingVersion),
     }?;
-    let mut got_file = false;
+    let message = format!(
+        "successful upload of version {} for project {}",
+        version.name(),
+        project.name()
+    );
+    let mut upload = Upload::new(store, project, version).await?;
+    for (key, value) in &params {
+        if let Some(file_name) = key.strip_prefix("sha256.") {
+            upload.expect_checksum(file_name.to_owned(), value.clone());
+        } else if let Some(label) = key.strip_prefix("label.") {
+            upload.add_label(label.to_owned(), value.clone());
+        } else if key == "commit" {
+            upload.set_commit(value.clone());
+        } else if key == "primary" {
+            upload.set_primary(value.clone());
+        }
+    }
+    receive_files(&mut upload, &mut multipart).await?;
+    upload.commit().await?;
+    Ok(message)
+}
+
+async fn receive_files(upload: &mut Upload, multipart: &mut Multipart) -> Result<(), StoreError> {
     while let Some(field) = multipart.next_field().await? {
-        let file_name = match field.file_name() {
-            Some(file_name) => file_name,
-            None => continue,
-        };
-        let outpath = store.outpath_for(&project, &version, file_name)?;
-        let bytes = field.bytes().await?;
-        fs::write(outpath, bytes).map_err(StoreError::IO)?;
-        got_file = true;
-    }
-    if got_file {
-        event!(
-            Level::INFO,
-            "uploaded version {} for project {}",
-            version.name(),
-            project.name()
-        );
-        Ok(format!(
-            "successful upload of version {} for project {}",
-            version.name(),
-            project.name()
-        ))
-    } else {
-        Err(StoreError::Other("failed to upload".to_string()).into())
+        match field.file_name() {
+            Some(file_name) => {
+                let file_name = file_name.to_owned();
+                upload.add_file(file_name, field).await?;
+            }
+            None if field.name() == Some("metadata") => upload.read_metadata(field).await?,
+            None => {}
+        }
     }
+    Ok(())
 }
@@ -0,0 +1,150 @@
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct VersionMetadata {
+    #[serde(default)]
+    pub uploaded_at: u64,
+    #[serde(default)]
+    pub uploader: Option<String>,
+    #[serde(default)]
+    pub commit: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub files: Vec<FileMetadata>,
+    #[serde(default)]
+    pub yanked: Option<Yank>,
+    #[serde(default)]
+    pub promoted_from: Option<Promotion>,
+    // the file downloaded from versions with more than one
+    #[serde(default)]
+    pub primary: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Yank {
+    pub yanked_at: u64,
+    #[serde(default)]
+    pub yanked_by: Option<String>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Promotion {
+    pub project: String,
+    pub version: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Channel {
+    pub version: String,
+    // oldest first, ending with the move to the current version
+    #[serde(default)]
+    pub history: Vec<ChannelMove>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChannelMove {
+    pub version: String,
+    pub moved_at: u64,
+    #[serde(default)]
+    pub moved_by: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileMetadata {
+    pub name: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub sha256: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProjectInfo {
+    pub name: String,
+    pub public: bool,
+    pub versions: usize,
+    pub latest: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct UploadMetadata {
+    #[serde(default)]
+    pub commit: Option<String>,
+    #[serde(default)]
+    pub primary: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct YankRequest {
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct DownloadRequest {
+    #[serde(default)]
+    pub file: Option<String>,
+    #[serde(default)]
+    pub direct: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PromoteRequest {
+    pub from: String,
+    pub version: String,
+    #[serde(default, rename = "move")]
+    pub move_version: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Promoted {
+    #[serde(flatten)]
+    pub metadata: VersionMetadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MoveChannelRequest {
+    pub version: String,
+}
+
+impl VersionMetadata {
+    pub fn file(&self, name: &str) -> Option<&FileMetadata> {
+        self.files.iter().find(|f| f.name == name)
+    }
+}
+
+impl FileMetadata {
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.sha256)
+    }
+
+    pub fn digest(&self) -> String {
+        let bytes = (0..self.sha256.len())
+            .step_by(2)
+            .filter_map(|i| u8::from_str_radix(self.sha256.get(i..i + 2)?, 16).ok())
+            .collect::<Vec<u8>>();
+        format!("sha-256={}", BASE64_STANDARD.encode(bytes))
+    }
+}
+
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn sha256_hex(hasher: Sha256) -> String {
+    format!("{:x}", hasher.finalize())
+}
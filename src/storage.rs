@@ -0,0 +1,248 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{self, AtomicU64};
+use std::time::SystemTime;
+
+// Keys are '/'-separated paths relative to the root of the storage, e.g.
+// "app/versions/1.0.0/version.json". Listing a prefix that doesn't exist
+// gives an empty list, and reading a key that doesn't exist fails with
+// io::ErrorKind::NotFound.
+pub trait Storage: Send + Sync + fmt::Debug {
+    fn list_dirs(&self, prefix: &str) -> io::Result<Vec<String>>;
+    fn list_files(&self, prefix: &str) -> io::Result<Vec<String>>;
+    fn is_dir(&self, key: &str) -> io::Result<bool>;
+    fn exists(&self, key: &str) -> io::Result<bool>;
+    fn modified(&self, key: &str) -> io::Result<SystemTime>;
+    fn read(&self, key: &str) -> io::Result<Vec<u8>>;
+    fn open(&self, key: &str) -> io::Result<Object>;
+    // like open, but answering the HTTP Range header `range` when the storage can serve
+    // ranges itself; local files are left for the caller to serve ranges from
+    fn open_range(&self, key: &str, _range: &str) -> io::Result<Object> {
+        self.open(key)
+    }
+    // replaces the whole object at once
+    fn write(&self, key: &str, contents: &[u8]) -> io::Result<()>;
+    // moves a local file to key, replacing whatever is there
+    fn put_file(&self, key: &str, path: &Path) -> io::Result<()>;
+    // moves a local staging directory under prefix, with the file `last` put
+    // in place last, failing with io::ErrorKind::AlreadyExists if `last` is
+    // already there
+    fn publish(&self, staging_dir: &Path, prefix: &str, last: &str) -> io::Result<()>;
+    // removes key and everything under it
+    fn delete(&self, key: &str) -> io::Result<()>;
+    // whether published directories appear all at once, rather than file by file
+    fn publishes_atomically(&self) -> bool;
+}
+
+pub enum Object {
+    Local(PathBuf),
+    Remote(Box<dyn Read + Send>),
+    // the part of a remote object asked for by open_range
+    Partial {
+        reader: Box<dyn Read + Send>,
+        content_range: String,
+        size: u64,
+    },
+    // open_range was asked for a range outside of the object
+    RangeNotSatisfiable,
+}
+
+impl Object {
+    pub fn into_reader(self) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            Object::Local(path) => Ok(Box::new(fs::File::open(path)?)),
+            Object::Remote(reader) | Object::Partial { reader, .. } => Ok(reader),
+            Object::RangeNotSatisfiable => Err(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+}
+
+pub fn read_dir(dir: &Path) -> io::Result<Vec<String>> {
+    fs::read_dir(dir)?
+        .map(|e| {
+            e?.file_name()
+                .into_string()
+                .map_err(|_s| io::Error::new(io::ErrorKind::InvalidData, "couldn't decode utf8"))
+        })
+        .collect()
+}
+
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut temp_path = path.to_path_buf();
+    temp_path.as_mut_os_string().push(format!(
+        ".tmp-{}-{}",
+        process::id(),
+        COUNTER.fetch_add(1, atomic::Ordering::Relaxed)
+    ));
+    let mut file = fs::File::create(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    match path.parent() {
+        Some(parent) => sync_dir(parent),
+        None => Ok(()),
+    }
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+fn sync_tree(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            sync_tree(&entry.path())?;
+        } else {
+            fs::File::open(entry.path())?.sync_all()?;
+        }
+    }
+    sync_dir(dir)
+}
+
+fn contains_files(dir: &Path) -> io::Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() || contains_files(&entry.path())? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn rename_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::rename(from, to).map_err(|e| match e.kind() {
+        io::ErrorKind::DirectoryNotEmpty => io::Error::from(io::ErrorKind::AlreadyExists),
+        _ => e,
+    })
+}
+
+// the layout of the state directory is the layout of the keys
+#[derive(Debug)]
+pub struct Filesystem {
+    root: PathBuf,
+    temp_counter: AtomicU64,
+}
+
+impl Filesystem {
+    pub fn new(root: PathBuf) -> Self {
+        Filesystem {
+            root,
+            temp_counter: AtomicU64::new(0),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let mut path = self.root.clone();
+        path.extend(key.split('/').filter(|s| !s.is_empty()));
+        path
+    }
+
+    fn list(&self, prefix: &str, dirs: bool) -> io::Result<Vec<String>> {
+        let dir = self.path(prefix);
+        let names = match read_dir(&dir) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            result => result?,
+        };
+        Ok(names
+            .into_iter()
+            .filter(|name| dir.join(name).is_dir() == dirs)
+            .collect())
+    }
+}
+
+impl Storage for Filesystem {
+    fn list_dirs(&self, prefix: &str) -> io::Result<Vec<String>> {
+        self.list(prefix, true)
+    }
+
+    fn list_files(&self, prefix: &str) -> io::Result<Vec<String>> {
+        self.list(prefix, false)
+    }
+
+    fn is_dir(&self, key: &str) -> io::Result<bool> {
+        Ok(self.path(key).is_dir())
+    }
+
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        Ok(self.path(key).is_file())
+    }
+
+    fn modified(&self, key: &str) -> io::Result<SystemTime> {
+        fs::metadata(self.path(key))?.modified()
+    }
+
+    fn read(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key))
+    }
+
+    fn open(&self, key: &str) -> io::Result<Object> {
+        let path = self.path(key);
+        if !path.is_file() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Ok(Object::Local(path))
+    }
+
+    fn write(&self, key: &str, contents: &[u8]) -> io::Result<()> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        write_atomic(&path, contents)
+    }
+
+    fn put_file(&self, key: &str, path: &Path) -> io::Result<()> {
+        let target = self.path(key);
+        let parent = target.parent().unwrap_or(&self.root);
+        fs::create_dir_all(parent)?;
+        fs::File::open(path)?.sync_all()?;
+        fs::rename(path, &target)?;
+        sync_dir(parent)
+    }
+
+    fn publish(&self, staging_dir: &Path, prefix: &str, _last: &str) -> io::Result<()> {
+        // a rename makes the whole directory visible at once, so there's no need to order files
+        sync_tree(staging_dir)?;
+        let target = self.path(prefix);
+        let parent = target.parent().unwrap_or(&self.root);
+        fs::create_dir_all(parent)?;
+        if let Err(e) = rename_dir(staging_dir, &target) {
+            if e.kind() != io::ErrorKind::AlreadyExists || contains_files(&target)? {
+                return Err(e);
+            }
+            // an empty directory left behind by an interrupted upload on an older server
+            fs::remove_dir_all(&target)?;
+            rename_dir(staging_dir, &target)?;
+        }
+        sync_dir(parent)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        let path = self.path(key);
+        let parent = path.parent().unwrap_or(&self.root);
+        if !path.is_dir() {
+            fs::remove_file(&path)?;
+            return sync_dir(parent);
+        }
+        // move the directory out of sight first so it disappears atomically
+        let mut staging_dir = self.root.join(".tmp");
+        fs::create_dir_all(&staging_dir)?;
+        staging_dir.push(format!(
+            "deleted-{}-{}",
+            process::id(),
+            self.temp_counter.fetch_add(1, atomic::Ordering::Relaxed)
+        ));
+        fs::rename(&path, &staging_dir)?;
+        sync_dir(parent)?;
+        fs::remove_dir_all(&staging_dir)
+    }
+
+    fn publishes_atomically(&self) -> bool {
+        true
+    }
+}
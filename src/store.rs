@@ -1,15 +1,56 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
 use std::fs;
-use std::io::{self, BufRead};
+use std::io;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
+use axum::extract::multipart::MultipartError;
 use axum::http::header;
 use axum::http::HeaderMap;
+use axum::http::HeaderValue;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
+use axum::Json;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{event, Level};
+
+use crate::config::*;
+use crate::metadata::*;
+use crate::storage::*;
+use crate::token::*;
+
+const METADATA_FILE: &str = "version.json";
+const TOKENS_FILE: &str = "tokens.json";
+const GROUPS_DIR: &str = "groups";
+const BLOBS_DIR: &str = "blobs";
+const CHANNELS_DIR: &str = "channels";
+const ADMINS_FILE: &str = "admins.txt";
+const FINGERPRINT_KEY_FILE: &str = "fingerprint.key";
+const PROJECT_CONFIG_FILE: &str = "project.json";
+const LATEST: &str = "latest";
+const LATEST_STABLE: &str = "latest-stable";
+// reserved in place of a version name to reserve a whole project, which no version can be named
+const WHOLE_PROJECT: &str = "";
 
 #[derive(Debug)]
 pub struct Store {
     dir: PathBuf,
+    storage: Box<dyn Storage>,
+    max_upload_size: Option<u64>,
+    temp_counter: AtomicU64,
+    reserved_versions: Arc<Mutex<HashSet<(String, String)>>>,
+    tokens_lock: Mutex<()>,
+    acl_lock: Mutex<()>,
+    metadata_lock: Mutex<()>,
+    blob_lock: Mutex<()>,
+    channels_lock: Mutex<()>,
+    fingerprint_key: Mutex<Option<Vec<u8>>>,
 }
 
 pub struct Credential {
@@ -20,17 +61,16 @@ impl Credential {
     pub fn from_headers(m: &HeaderMap) -> Result<Self, StoreError> {
         match m.get(header::AUTHORIZATION) {
             Some(x) => {
-                let val = x
-                    .to_str()
-                    .map_err(|_| StoreError::Other("bad header encoding".to_string()))?;
-                if val.starts_with("Bearer ") {
+                let val = x.to_str().map_err(|_| StoreError::InvalidAuthorization)?;
+                if let Some(token) = val.strip_prefix("Bearer ") {
+                    if token.is_empty() {
+                        return Err(StoreError::InvalidAuthorization);
+                    }
                     Ok(Credential {
-                        token: val[7..].to_owned(),
+                        token: token.to_owned(),
                     })
                 } else {
-                    Err(StoreError::Other(
-                        "unknown authentication method".to_string(),
-                    ))
+                    Err(StoreError::UnsupportedAuthorization)
                 }
             }
             None => Err(StoreError::UnprovidedAuthorization),
@@ -44,10 +84,7 @@ struct Project {
 
 impl Project {
     fn new(name: String) -> Result<Self, StoreError> {
-        if !name
-            .chars()
-            .all(|c| c.is_alphanumeric() | ['-', '_'].contains(&c))
-        {
+        if !valid_name(&name) || name == GROUPS_DIR || name == BLOBS_DIR {
             return Err(StoreError::InvalidProject);
         }
         Ok(Project { name })
@@ -56,99 +93,458 @@ impl Project {
 
 pub struct ProjectReader {
     name: String,
+    identity: Identity,
 }
 
 impl ProjectReader {
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
 }
 
 pub struct ProjectWriter {
-    name: String,
+    reader: ProjectReader,
 }
 
 impl ProjectWriter {
     pub fn name(&self) -> &str {
-        &self.name
+        &self.reader.name
+    }
+
+    pub fn identity(&self) -> &Identity {
+        self.reader.identity()
     }
 
     pub fn reader(&self) -> &ProjectReader {
-        unsafe { &*(self as *const ProjectWriter as *const ProjectReader) }
+        &self.reader
+    }
+}
+
+pub struct ProjectDeleter {
+    writer: ProjectWriter,
+}
+
+impl ProjectDeleter {
+    pub fn name(&self) -> &str {
+        self.writer.name()
+    }
+
+    pub fn identity(&self) -> &Identity {
+        self.writer.identity()
+    }
+
+    pub fn writer(&self) -> &ProjectWriter {
+        &self.writer
+    }
+}
+
+pub struct Admin {
+    identity: Identity,
+}
+
+impl Admin {
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclRole {
+    Readers,
+    Writers,
+}
+
+impl AclRole {
+    fn file_name(self) -> &'static str {
+        match self {
+            AclRole::Readers => "readers.txt",
+            AclRole::Writers => "writers.txt",
+        }
     }
 }
 
+#[derive(Serialize, Debug)]
+pub struct ProjectAcl {
+    pub config: ProjectConfig,
+    pub readers: Vec<String>,
+    pub writers: Vec<String>,
+}
+
 pub struct Version {
     name: String,
 }
 
 impl Version {
     pub fn new(name: String) -> Result<Self, StoreError> {
-        if !name
-            .chars()
-            .all(|c| c.is_alphanumeric() | ['-', '_', '.'].contains(&c))
-            && name.chars().next() != Some('.')
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_alphanumeric() | ['-', '_', '.'].contains(&c))
+            || name.starts_with('.')
+            || [LATEST, LATEST_STABLE].contains(&name.as_str())
         {
             return Err(StoreError::InvalidVersion);
         }
         Ok(Version { name })
     }
 
+    pub fn semver(&self) -> Option<semver::Version> {
+        semver::Version::parse(self.name.strip_prefix('v').unwrap_or(&self.name)).ok()
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 }
 
+pub struct VersionReservation {
+    reserved_versions: Arc<Mutex<HashSet<(String, String)>>>,
+    key: (String, String),
+}
+
+impl Drop for VersionReservation {
+    fn drop(&mut self) {
+        self.reserved_versions.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[derive(Debug)]
 pub enum StoreError {
     IO(io::Error),
+    Multipart(MultipartError),
     InvalidProject,
     InvalidVersion,
     InvalidFile,
+    InvalidChannel,
+    ProjectNotFound,
+    VersionNotFound,
+    FileNotFound,
+    ChannelNotFound,
+    VersionExists,
+    VersionUploadInProgress,
+    ProjectBusy,
+    VersionInChannel(String),
+    PayloadTooLarge(u64),
+    ChecksumMismatch(String),
+    InvalidMetadata(String),
+    MissingVersion,
+    NoFiles,
+    DuplicateFile(String),
+    MultipleFiles,
     CorruptedVersion,
     UnprovidedAuthorization,
-    Other(String),
+    InvalidAuthorization,
+    UnsupportedAuthorization,
+    TokenExpired,
+    MissingScope(Scope),
+    TokenExists(String),
+    InvalidTokenName,
+    InvalidAclEntry,
+    InvalidConfig(String),
+    ProjectExists,
+    UnauthorizedReader,
+    UnauthorizedWriter,
+    UnauthorizedAdmin,
+}
+
+impl StoreError {
+    pub fn status(&self) -> StatusCode {
+        use StoreError::*;
+        match self {
+            IO(_) | CorruptedVersion => StatusCode::INTERNAL_SERVER_ERROR,
+            Multipart(e) => e.status(),
+            InvalidProject | InvalidVersion | InvalidFile | InvalidChannel | MissingVersion
+            | NoFiles | ChecksumMismatch(_) | InvalidMetadata(_) | DuplicateFile(_)
+            | MultipleFiles => StatusCode::BAD_REQUEST,
+            ProjectNotFound | VersionNotFound | FileNotFound | ChannelNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            VersionExists | VersionUploadInProgress | VersionInChannel(_) | ProjectBusy => {
+                StatusCode::CONFLICT
+            }
+            PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            UnprovidedAuthorization
+            | InvalidAuthorization
+            | UnsupportedAuthorization
+            | TokenExpired => StatusCode::UNAUTHORIZED,
+            MissingScope(_) | UnauthorizedReader | UnauthorizedWriter | UnauthorizedAdmin => {
+                StatusCode::FORBIDDEN
+            }
+            TokenExists(_) | ProjectExists => StatusCode::CONFLICT,
+            InvalidTokenName | InvalidAclEntry | InvalidConfig(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        use StoreError::*;
+        match self {
+            IO(_) => "io_error",
+            Multipart(_) => "invalid_multipart",
+            InvalidProject => "invalid_project",
+            InvalidVersion => "invalid_version",
+            InvalidFile => "invalid_file",
+            InvalidChannel => "invalid_channel",
+            ProjectNotFound => "project_not_found",
+            VersionNotFound => "version_not_found",
+            FileNotFound => "file_not_found",
+            ChannelNotFound => "channel_not_found",
+            VersionExists => "version_exists",
+            VersionUploadInProgress => "version_upload_in_progress",
+            ProjectBusy => "project_busy",
+            VersionInChannel(_) => "version_in_channel",
+            PayloadTooLarge(_) => "payload_too_large",
+            ChecksumMismatch(_) => "checksum_mismatch",
+            InvalidMetadata(_) => "invalid_metadata",
+            MissingVersion => "missing_version",
+            NoFiles => "no_files",
+            DuplicateFile(_) => "duplicate_file",
+            MultipleFiles => "multiple_files",
+            CorruptedVersion => "corrupted_version",
+            UnprovidedAuthorization => "missing_authorization",
+            InvalidAuthorization => "invalid_authorization",
+            UnsupportedAuthorization => "unsupported_authorization",
+            TokenExpired => "token_expired",
+            MissingScope(_) => "missing_scope",
+            TokenExists(_) => "token_exists",
+            UnauthorizedReader => "unauthorized_reader",
+            UnauthorizedWriter => "unauthorized_writer",
+            InvalidTokenName => "invalid_token_name",
+            InvalidAclEntry => "invalid_acl_entry",
+            InvalidConfig(_) => "invalid_config",
+            ProjectExists => "project_exists",
+            UnauthorizedAdmin => "unauthorized_admin",
+        }
+    }
+
+    fn www_authenticate(&self) -> Option<&'static str> {
+        use StoreError::*;
+        match self {
+            UnprovidedAuthorization => Some("Bearer realm=\"artifacts-r-us\""),
+            InvalidAuthorization | UnsupportedAuthorization => {
+                Some("Bearer realm=\"artifacts-r-us\", error=\"invalid_request\"")
+            }
+            TokenExpired => Some("Bearer realm=\"artifacts-r-us\", error=\"invalid_token\""),
+            MissingScope(_) | UnauthorizedReader | UnauthorizedWriter | UnauthorizedAdmin => {
+                Some("Bearer realm=\"artifacts-r-us\", error=\"insufficient_scope\"")
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use StoreError::*;
+        match self {
+            IO(e) => write!(f, "{}", e),
+            Multipart(e) => write!(f, "{}", e.body_text()),
+            InvalidProject => write!(f, "invalid project name"),
+            InvalidVersion => write!(f, "invalid version name"),
+            InvalidFile => write!(f, "invalid file name"),
+            InvalidChannel => write!(f, "invalid channel name"),
+            ProjectNotFound => write!(f, "project does not exist"),
+            VersionNotFound => write!(f, "version does not exist"),
+            FileNotFound => write!(f, "file does not exist in version"),
+            ChannelNotFound => write!(f, "channel does not exist"),
+            VersionExists => write!(f, "version already exists"),
+            VersionUploadInProgress => write!(f, "version is already being uploaded"),
+            ProjectBusy => write!(f, "project is being changed by another request"),
+            VersionInChannel(channel) => write!(f, "channel {} points at this version", channel),
+            PayloadTooLarge(limit) => write!(f, "upload exceeds maximum size of {} bytes", limit),
+            ChecksumMismatch(name) => write!(f, "checksum mismatch for file {}", name),
+            InvalidMetadata(e) => write!(f, "invalid metadata: {}", e),
+            MissingVersion => write!(f, "did not provide version"),
+            NoFiles => write!(f, "upload did not contain any files"),
+            DuplicateFile(name) => write!(f, "duplicate file {}", name),
+            MultipleFiles => write!(f, "version has more than one file to choose from"),
+            CorruptedVersion => write!(f, "corrupted storage for version"),
+            UnprovidedAuthorization => write!(f, "did not provide authorization"),
+            InvalidAuthorization => write!(f, "bad authorization header encoding"),
+            UnsupportedAuthorization => write!(f, "unknown authentication method"),
+            TokenExpired => write!(f, "token has expired"),
+            MissingScope(scope) => write!(f, "token does not have the {} scope", scope),
+            TokenExists(name) => write!(f, "a token named {} already exists", name),
+            UnauthorizedReader => write!(f, "unauthorized reader"),
+            UnauthorizedWriter => write!(f, "unauthorized writer"),
+            InvalidTokenName => write!(f, "invalid token name"),
+            InvalidAclEntry => {
+                write!(
+                    f,
+                    "ACL entry must be exactly one of a token, token name or group"
+                )
+            }
+            InvalidConfig(e) => write!(f, "invalid project configuration: {}", e),
+            ProjectExists => write!(f, "project already exists"),
+            UnauthorizedAdmin => write!(f, "unauthorized administrator"),
+        }
+    }
+}
+
+impl From<MultipartError> for StoreError {
+    fn from(e: MultipartError) -> Self {
+        StoreError::Multipart(e)
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: &'static str,
+    message: String,
 }
 
 impl IntoResponse for StoreError {
     fn into_response(self) -> axum::response::Response {
-        use StoreError::*;
-        let body = match self {
-            IO(e) => e.to_string(),
-            InvalidProject => "invalid project name".to_string(),
-            InvalidVersion => "invalid version name".to_string(),
-            InvalidFile => "invalid file for version".to_string(),
-            CorruptedVersion => "corrupted storage for version".to_string(),
-            UnprovidedAuthorization => "did not provide authorization".to_string(),
-            Other(s) => s,
+        if self.status().is_server_error() {
+            event!(Level::ERROR, "{}", self);
+        }
+        let body = ErrorBody {
+            error: self.code(),
+            message: self.to_string(),
         };
-        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+        let mut response = (self.status(), Json(body)).into_response();
+        if let Some(challenge) = self.www_authenticate() {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static(challenge),
+            );
+        }
+        response
     }
 }
 
-fn read_dir(dir: &Path) -> Result<Vec<String>, StoreError> {
-    let contents = fs::read_dir(dir).map_err(StoreError::IO)?;
-    contents
-        .map(|e| {
-            let e = e?;
-            e.file_name()
-                .into_string()
-                .map_err(|_s| io::Error::new(io::ErrorKind::InvalidData, "couldn't decode utf8"))
-        })
-        .collect::<Result<_, io::Error>>()
-        .map_err(StoreError::IO)
+pub fn validate_file_name(name: &str) -> Result<(), StoreError> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.chars().any(|c| ['/', '\\', '\0'].contains(&c))
+    {
+        return Err(StoreError::InvalidFile);
+    }
+    Ok(())
 }
 
-fn file_contains<P: AsRef<Path>>(filename: P, line: &str) -> Result<bool, io::Error> {
-    let file = fs::File::open(filename)?;
-    Ok(io::BufReader::new(file).lines().any(|l| match l {
-        Ok(l) => l == line,
-        Err(_) => false,
-    }))
+fn encode_metadata(metadata: &VersionMetadata) -> Result<Vec<u8>, StoreError> {
+    serde_json::to_vec_pretty(metadata).map_err(|e| StoreError::IO(e.into()))
+}
+
+fn encode_config(config: &ProjectConfig) -> Result<Vec<u8>, StoreError> {
+    config
+        .validate()
+        .map_err(|e| StoreError::InvalidConfig(e.to_string()))?;
+    serde_json::to_vec_pretty(config).map_err(|e| StoreError::IO(e.into()))
+}
+
+fn encode_lines(lines: &[String]) -> Vec<u8> {
+    let mut contents = String::new();
+    for line in lines {
+        contents.push_str(line);
+        contents.push('\n');
+    }
+    contents.into_bytes()
+}
+
+// the order versions are listed in, and that `latest` picks the last of: semantic versions
+// by precedence after all other versions, which go by upload time. Upload times are only
+// compared between versions that aren't semantic versions
+fn compare_versions(a: (&Version, u64), b: (&Version, u64)) -> Ordering {
+    let ((a, a_uploaded_at), (b, b_uploaded_at)) = (a, b);
+    match (a.semver(), b.semver()) {
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Greater,
+        (None, Some(_)) => Ordering::Less,
+        (None, None) => a_uploaded_at.cmp(&b_uploaded_at),
+    }
+    .then_with(|| a.name.cmp(&b.name))
+}
+
+fn rename_error(e: io::Error) -> StoreError {
+    match e.kind() {
+        io::ErrorKind::AlreadyExists => StoreError::VersionExists,
+        _ => StoreError::IO(e),
+    }
 }
 
 impl Store {
-    pub fn new(dir: String) -> Self {
-        Store { dir: dir.into() }
+    pub fn new(dir: String, storage: Box<dyn Storage>, max_upload_size: Option<u64>) -> Self {
+        Store {
+            dir: dir.into(),
+            storage,
+            max_upload_size,
+            temp_counter: AtomicU64::new(0),
+            reserved_versions: Arc::new(Mutex::new(HashSet::new())),
+            tokens_lock: Mutex::new(()),
+            acl_lock: Mutex::new(()),
+            metadata_lock: Mutex::new(()),
+            blob_lock: Mutex::new(()),
+            channels_lock: Mutex::new(()),
+            fingerprint_key: Mutex::new(None),
+        }
+    }
+
+    // Store methods do blocking I/O, so async code calls them through this, which runs
+    // them on tokio's blocking thread pool instead of the runtime's worker threads
+    pub async fn run_blocking<T, F>(self: &Arc<Self>, f: F) -> Result<T, StoreError>
+    where
+        F: FnOnce(&Store) -> Result<T, StoreError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || f(&store))
+            .await
+            .map_err(|e| StoreError::IO(io::Error::other(e)))?
+    }
+
+    pub fn max_upload_size(&self) -> Option<u64> {
+        self.max_upload_size
+    }
+
+    fn staging_root(&self) -> PathBuf {
+        let mut path = self.dir.clone();
+        path.push(".tmp");
+        path
+    }
+
+    fn staging_path(&self, kind: &str) -> Result<PathBuf, StoreError> {
+        let mut path = self.staging_root();
+        fs::create_dir_all(&path).map_err(StoreError::IO)?;
+        path.push(format!(
+            "{}-{}-{}",
+            kind,
+            process::id(),
+            self.temp_counter.fetch_add(1, atomic::Ordering::Relaxed)
+        ));
+        Ok(path)
+    }
+
+    pub fn create_staging_dir(&self) -> Result<PathBuf, StoreError> {
+        let mut path = self.staging_path("upload")?;
+        path.push("files");
+        fs::create_dir_all(&path).map_err(StoreError::IO)?;
+        path.pop();
+        Ok(path)
+    }
+
+    pub fn sweep_staging(&self) -> Result<usize, StoreError> {
+        let staging_root = self.staging_root();
+        let entries = match read_dir(&staging_root) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            result => result.map_err(StoreError::IO)?,
+        };
+        for entry in &entries {
+            let mut path = staging_root.clone();
+            path.push(entry);
+            if path.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            }
+            .map_err(StoreError::IO)?;
+        }
+        Ok(entries.len())
     }
 
     pub fn project_reader(
@@ -156,10 +552,26 @@ impl Store {
         project_name: String,
         headers: &HeaderMap,
     ) -> Result<ProjectReader, StoreError> {
-        let cred = Credential::from_headers(headers)?;
         let project = Project::new(project_name)?;
-        self.authorized_reader(&cred, &project)?;
-        Ok(ProjectReader { name: project.name })
+        let cred = match Credential::from_headers(headers) {
+            Err(StoreError::UnprovidedAuthorization) if self.is_public(&project)? => {
+                return Ok(ProjectReader {
+                    name: project.name,
+                    identity: Identity::anonymous(),
+                })
+            }
+            cred => cred?,
+        };
+        let identity = self.identify(&cred)?;
+        match self.authorized_reader(&cred, &identity, &project) {
+            Err(StoreError::UnauthorizedReader | StoreError::MissingScope(_))
+                if self.is_public(&project)? => {}
+            result => result?,
+        }
+        Ok(ProjectReader {
+            name: project.name,
+            identity,
+        })
     }
 
     pub fn project_writer(
@@ -168,93 +580,1243 @@ impl Store {
         headers: &HeaderMap,
     ) -> Result<ProjectWriter, StoreError> {
         let cred = Credential::from_headers(headers)?;
+        let identity = self.identify(&cred)?;
         let project = Project::new(project_name)?;
-        self.authorized_writer(&cred, &project)?;
-        Ok(ProjectWriter { name: project.name })
+        self.authorized_writer(&cred, &identity, &project, Scope::Write)?;
+        Ok(ProjectWriter {
+            reader: ProjectReader {
+                name: project.name,
+                identity,
+            },
+        })
+    }
+
+    pub fn project_deleter(
+        &self,
+        project_name: String,
+        headers: &HeaderMap,
+    ) -> Result<ProjectDeleter, StoreError> {
+        let cred = Credential::from_headers(headers)?;
+        let identity = self.identify(&cred)?;
+        let project = Project::new(project_name)?;
+        self.authorized_writer(&cred, &identity, &project, Scope::Delete)?;
+        Ok(ProjectDeleter {
+            writer: ProjectWriter {
+                reader: ProjectReader {
+                    name: project.name,
+                    identity,
+                },
+            },
+        })
+    }
+
+    fn read_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, StoreError> {
+        match self.storage.read(key) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map(Some)
+                .map_err(|e| StoreError::IO(e.into())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(StoreError::IO(e)),
+        }
+    }
+
+    fn read_lines(&self, key: &str) -> Result<Vec<String>, StoreError> {
+        let contents = match self.storage.read(key) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(StoreError::IO(e)),
+        };
+        Ok(String::from_utf8_lossy(&contents)
+            .lines()
+            .map(|l| l.trim().to_owned())
+            .collect())
+    }
+
+    pub fn list_tokens(&self) -> Result<Vec<TokenRecord>, StoreError> {
+        Ok(self.read_json(TOKENS_FILE)?.unwrap_or_default())
+    }
+
+    pub fn add_token(&self, record: TokenRecord) -> Result<(), StoreError> {
+        if !valid_name(&record.name) {
+            return Err(StoreError::InvalidTokenName);
+        }
+        let _guard = self.tokens_lock.lock().unwrap();
+        let mut tokens = self.list_tokens()?;
+        if tokens.iter().any(|t| t.name == record.name) {
+            return Err(StoreError::TokenExists(record.name));
+        }
+        tokens.push(record);
+        let contents = serde_json::to_vec_pretty(&tokens).map_err(|e| StoreError::IO(e.into()))?;
+        self.storage
+            .write(TOKENS_FILE, &contents)
+            .map_err(StoreError::IO)
+    }
+
+    fn identify(&self, cred: &Credential) -> Result<Identity, StoreError> {
+        match self
+            .list_tokens()?
+            .iter()
+            .find(|t| token_matches(&t.hash, &cred.token))
+        {
+            Some(record) => {
+                if record
+                    .expires_at
+                    .is_some_and(|expires_at| expires_at <= unix_time(SystemTime::now()))
+                {
+                    event!(Level::INFO, "rejected expired token {}", record.name);
+                    return Err(StoreError::TokenExpired);
+                }
+                Ok(Identity::registered(record))
+            }
+            None => Ok(Identity::unregistered(
+                &cred.token,
+                &self.fingerprint_key()?,
+            )),
+        }
+    }
+
+    // the secret that unregistered tokens' names are derived with, made on first use
+    fn fingerprint_key(&self) -> Result<Vec<u8>, StoreError> {
+        let mut fingerprint_key = self.fingerprint_key.lock().unwrap();
+        if let Some(key) = &*fingerprint_key {
+            return Ok(key.clone());
+        }
+        let key = match self.storage.read(FINGERPRINT_KEY_FILE) {
+            Ok(key) => key,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = random_hex(32).into_bytes();
+                self.storage
+                    .write(FINGERPRINT_KEY_FILE, &key)
+                    .map_err(StoreError::IO)?;
+                key
+            }
+            Err(e) => return Err(StoreError::IO(e)),
+        };
+        *fingerprint_key = Some(key.clone());
+        Ok(key)
+    }
+
+    fn group_key(&self, group: &str) -> String {
+        format!("{}/{}.txt", GROUPS_DIR, group)
+    }
+
+    fn acl_key(&self, project_name: &str, role: AclRole) -> String {
+        format!("{}/{}", project_name, role.file_name())
+    }
+
+    fn acl_contains(
+        &self,
+        key: &str,
+        cred: &Credential,
+        identity: &Identity,
+    ) -> Result<bool, StoreError> {
+        for line in self.read_lines(key)? {
+            let matches = match line.strip_prefix('@') {
+                Some(group) if valid_name(group) => self
+                    .read_lines(&self.group_key(group))?
+                    .iter()
+                    .any(|l| line_matches(l, &cred.token, identity)),
+                Some(_) => false,
+                None => line_matches(&line, &cred.token, identity),
+            };
+            if matches {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn is_admin(&self, cred: &Credential, identity: &Identity) -> Result<bool, StoreError> {
+        if identity.has_scope(Scope::Admin) {
+            return Ok(true);
+        }
+        self.acl_contains(ADMINS_FILE, cred, identity)
     }
 
-    fn authorized_reader(&self, cred: &Credential, project: &Project) -> Result<(), StoreError> {
-        let mut reader_list_path = self.dir.clone();
-        reader_list_path.push(&project.name);
-        reader_list_path.push("readers.txt");
-        if file_contains(reader_list_path, &cred.token).map_err(StoreError::IO)? {
+    fn project_config(&self, project: &Project) -> Result<ProjectConfig, StoreError> {
+        let key = format!("{}/{}", self.project_key(project)?, PROJECT_CONFIG_FILE);
+        Ok(self.read_json(&key)?.unwrap_or_default())
+    }
+
+    fn is_public(&self, project: &Project) -> Result<bool, StoreError> {
+        match self.project_config(project) {
+            Err(StoreError::ProjectNotFound) => Ok(false),
+            config => Ok(config?.public),
+        }
+    }
+
+    fn project_key(&self, project: &Project) -> Result<String, StoreError> {
+        if !self.storage.is_dir(&project.name).map_err(StoreError::IO)? {
+            return Err(StoreError::ProjectNotFound);
+        }
+        Ok(project.name.clone())
+    }
+
+    fn authorized_reader(
+        &self,
+        cred: &Credential,
+        identity: &Identity,
+        project: &Project,
+    ) -> Result<(), StoreError> {
+        // only administrators get to find out whether a project exists; everyone else is
+        // denied the same way for missing projects as for projects they can't access
+        if self.is_admin(cred, identity)? {
+            self.project_key(project)?;
+            return Ok(());
+        }
+        if !identity.has_scope(Scope::Read) {
+            return Err(StoreError::MissingScope(Scope::Read));
+        }
+        let readers = self.acl_key(&project.name, AclRole::Readers);
+        if self.acl_contains(&readers, cred, identity)? {
             Ok(())
         } else {
-            Err(StoreError::Other("unauthorized reader".to_string()))
+            event!(
+                Level::INFO,
+                "denied {} read access to project {}",
+                identity.name(),
+                project.name
+            );
+            Err(StoreError::UnauthorizedReader)
+        }
+    }
+
+    fn can_read(
+        &self,
+        cred: &Credential,
+        identity: &Identity,
+        project: &Project,
+        public: bool,
+    ) -> Result<bool, StoreError> {
+        if public || self.is_admin(cred, identity)? {
+            return Ok(true);
         }
+        let readers = self.acl_key(&project.name, AclRole::Readers);
+        Ok(identity.has_scope(Scope::Read) && self.acl_contains(&readers, cred, identity)?)
     }
 
-    fn authorized_writer(&self, cred: &Credential, project: &Project) -> Result<(), StoreError> {
-        let mut writer_list_path = self.dir.clone();
-        writer_list_path.push(&project.name);
-        writer_list_path.push("writers.txt");
-        if file_contains(writer_list_path, &cred.token).map_err(StoreError::IO)? {
+    pub fn list_readable_projects(
+        &self,
+        headers: &HeaderMap,
+    ) -> Result<Vec<ProjectInfo>, StoreError> {
+        let cred = Credential::from_headers(headers)?;
+        let identity = self.identify(&cred)?;
+        let mut projects = Vec::new();
+        for name in self.list_projects()? {
+            let project = Project::new(name)?;
+            // a broken project.json only hides that project, rather than failing the list
+            let public = match self.is_public(&project) {
+                Ok(public) => public,
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "skipping project {} when listing projects: {}",
+                        project.name,
+                        e
+                    );
+                    continue;
+                }
+            };
+            if !self.can_read(&cred, &identity, &project, public)? {
+                continue;
+            }
+            let reader = ProjectReader {
+                name: project.name,
+                identity: identity.clone(),
+            };
+            let (versions, latest) = self.summarize_versions(&reader)?;
+            projects.push(ProjectInfo {
+                name: reader.name,
+                public,
+                versions,
+                latest: latest.map(|version| version.name),
+            });
+        }
+        Ok(projects)
+    }
+
+    // counts every version, yanked or not, and only loads metadata until it finds the
+    // latest one, since loading all of it for every project makes listing them slow
+    fn summarize_versions(
+        &self,
+        project: &ProjectReader,
+    ) -> Result<(usize, Option<Version>), StoreError> {
+        let (mut semver, other): (Vec<_>, Vec<_>) = self
+            .storage
+            .list_dirs(&self.versions_key(project))
+            .map_err(StoreError::IO)?
+            .into_iter()
+            .filter_map(|name| Version::new(name).ok())
+            .filter(|version| self.version_exists(project, version))
+            .partition(|version| version.semver().is_some());
+        let count = semver.len() + other.len();
+        let visible_metadata = |version: &Version| match self.version_metadata(project, version) {
+            Ok(metadata) => metadata.yanked.is_none().then_some(metadata),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "skipping version {} of project {} when listing projects: {}",
+                    version.name,
+                    project.name,
+                    e
+                );
+                None
+            }
+        };
+        // semantic versions sort after all others, so the latest can be found from their
+        // names alone unless every one of them has been yanked
+        semver.sort_by(|a, b| compare_versions((a, 0), (b, 0)));
+        if let Some(latest) = semver
+            .into_iter()
+            .rev()
+            .find(|v| visible_metadata(v).is_some())
+        {
+            return Ok((count, Some(latest)));
+        }
+        let latest = other
+            .into_iter()
+            .filter_map(|version| Some((visible_metadata(&version)?.uploaded_at, version)))
+            .max_by(|(a_uploaded_at, a), (b_uploaded_at, b)| {
+                compare_versions((a, *a_uploaded_at), (b, *b_uploaded_at))
+            })
+            .map(|(_, version)| version);
+        Ok((count, latest))
+    }
+
+    fn authorized_writer(
+        &self,
+        cred: &Credential,
+        identity: &Identity,
+        project: &Project,
+        scope: Scope,
+    ) -> Result<(), StoreError> {
+        if self.is_admin(cred, identity)? {
+            self.project_key(project)?;
+            return Ok(());
+        }
+        if !identity.has_scope(scope) {
+            return Err(StoreError::MissingScope(scope));
+        }
+        let writers = self.acl_key(&project.name, AclRole::Writers);
+        if self.acl_contains(&writers, cred, identity)? {
             Ok(())
         } else {
-            Err(StoreError::Other("unauthorized writer".to_string()))
+            event!(
+                Level::INFO,
+                "denied {} {} access to project {}",
+                identity.name(),
+                scope,
+                project.name
+            );
+            Err(StoreError::UnauthorizedWriter)
+        }
+    }
+
+    fn token_lists(&self) -> Result<Vec<String>, StoreError> {
+        let mut lists = vec![ADMINS_FILE.to_owned()];
+        for group in self
+            .storage
+            .list_files(GROUPS_DIR)
+            .map_err(StoreError::IO)?
+        {
+            if group.ends_with(".txt") {
+                lists.push(format!("{}/{}", GROUPS_DIR, group));
+            }
+        }
+        for project in self.list_projects()? {
+            for role in [AclRole::Readers, AclRole::Writers] {
+                lists.push(self.acl_key(&project, role));
+            }
+        }
+        Ok(lists)
+    }
+
+    pub fn migrate_tokens(&self) -> Result<usize, StoreError> {
+        let mut migrated = 0;
+        for key in self.token_lists()? {
+            let contents = match self.storage.read(&key) {
+                Ok(contents) => String::from_utf8_lossy(&contents).into_owned(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(StoreError::IO(e)),
+            };
+            if !contents.lines().any(|l| is_plaintext(l.trim())) {
+                continue;
+            }
+            let mut rewritten = String::new();
+            for line in contents.lines() {
+                if is_plaintext(line.trim()) {
+                    rewritten.push_str(&hash_token(line.trim()));
+                    migrated += 1;
+                } else {
+                    rewritten.push_str(line);
+                }
+                rewritten.push('\n');
+            }
+            self.storage
+                .write(&key, rewritten.as_bytes())
+                .map_err(StoreError::IO)?;
         }
+        Ok(migrated)
     }
 
     pub fn list_projects(&self) -> Result<Vec<String>, StoreError> {
-        read_dir(&self.dir)
+        let mut projects = self
+            .storage
+            .list_dirs("")
+            .map_err(StoreError::IO)?
+            .into_iter()
+            .filter(|name| Project::new(name.clone()).is_ok())
+            .collect::<Vec<_>>();
+        projects.sort();
+        Ok(projects)
     }
 
-    fn versions_dir(&self, project: &ProjectReader) -> PathBuf {
-        let mut versions_dir = self.dir.clone();
-        versions_dir.push(&project.name);
-        versions_dir.push("versions");
-        versions_dir
+    fn versions_key(&self, project: &ProjectReader) -> String {
+        format!("{}/versions", project.name)
     }
 
     pub fn list_versions(&self, project: &ProjectReader) -> Result<Vec<String>, StoreError> {
-        read_dir(&self.versions_dir(project))
+        Ok(self
+            .visible_versions(project)?
+            .into_iter()
+            .map(|(version, _)| version.name)
+            .collect())
+    }
+
+    fn sorted_versions(
+        &self,
+        project: &ProjectReader,
+    ) -> Result<Vec<(Version, VersionMetadata)>, StoreError> {
+        let names = self
+            .storage
+            .list_dirs(&self.versions_key(project))
+            .map_err(StoreError::IO)?;
+        let mut versions = Vec::new();
+        for name in names {
+            let version = match Version::new(name) {
+                Ok(version) => version,
+                Err(_) => continue,
+            };
+            if !self.version_exists(project, &version) {
+                continue;
+            }
+            let metadata = self.version_metadata(project, &version)?;
+            versions.push((version, metadata));
+        }
+        versions.sort_by(|(a, a_metadata), (b, b_metadata)| {
+            compare_versions((a, a_metadata.uploaded_at), (b, b_metadata.uploaded_at))
+        });
+        Ok(versions)
+    }
+
+    fn visible_versions(
+        &self,
+        project: &ProjectReader,
+    ) -> Result<Vec<(Version, VersionMetadata)>, StoreError> {
+        let mut versions = self.sorted_versions(project)?;
+        versions.retain(|(_, metadata)| metadata.yanked.is_none());
+        Ok(versions)
+    }
+
+    pub fn resolve_version(
+        &self,
+        project: &ProjectReader,
+        name: String,
+    ) -> Result<Version, StoreError> {
+        let stable_only = match name.as_str() {
+            LATEST => false,
+            LATEST_STABLE => true,
+            _ => return Version::new(name),
+        };
+        self.visible_versions(project)?
+            .into_iter()
+            .map(|(version, _)| version)
+            .rfind(|version| !stable_only || version.semver().is_none_or(|v| v.pre.is_empty()))
+            .ok_or(StoreError::VersionNotFound)
+    }
+
+    fn version_key(&self, project: &ProjectReader, version: &Version) -> String {
+        format!("{}/{}", self.versions_key(project), version.name)
+    }
+
+    // versions uploaded before blobs keep their files directly in the version's directory
+    fn file_key(&self, project: &ProjectReader, version: &Version, file_name: &str) -> String {
+        format!("{}/{}", self.version_key(project, version), file_name)
+    }
+
+    pub fn list_files(
+        &self,
+        project: &ProjectReader,
+        version: &Version,
+    ) -> Result<Vec<String>, StoreError> {
+        let mut files = self
+            .version_metadata(project, version)?
+            .files
+            .into_iter()
+            .map(|f| f.name)
+            .collect::<Vec<_>>();
+        files.sort();
+        Ok(files)
+    }
+
+    fn metadata_key(&self, project: &ProjectReader, version: &Version) -> String {
+        format!("{}/{}", self.version_key(project, version), METADATA_FILE)
     }
 
+    pub fn version_metadata(
+        &self,
+        project: &ProjectReader,
+        version: &Version,
+    ) -> Result<VersionMetadata, StoreError> {
+        if !self.version_exists(project, version) {
+            return Err(StoreError::VersionNotFound);
+        }
+        let key = self.metadata_key(project, version);
+        if let Some(metadata) = self.read_json(&key)? {
+            return Ok(metadata);
+        }
+        // versions uploaded before checksums were recorded get them on first access
+        let mut files = self
+            .storage
+            .list_files(&self.version_key(project, version))
+            .map_err(StoreError::IO)?;
+        if files.is_empty() {
+            return Err(StoreError::CorruptedVersion);
+        }
+        files.sort();
+        let mut metadata = VersionMetadata::default();
+        for name in files {
+            let file_key = self.file_key(project, version, &name);
+            let modified = self.storage.modified(&file_key).map_err(StoreError::IO)?;
+            metadata.uploaded_at = metadata.uploaded_at.max(unix_time(modified));
+            let mut hasher = Sha256::new();
+            let size = io::copy(
+                &mut self
+                    .storage
+                    .open(&file_key)
+                    .and_then(Object::into_reader)
+                    .map_err(StoreError::IO)?,
+                &mut hasher,
+            )
+            .map_err(StoreError::IO)?;
+            metadata.files.push(FileMetadata {
+                name,
+                size,
+                content_type: None,
+                sha256: sha256_hex(hasher),
+            });
+        }
+        self.storage
+            .write(&key, &encode_metadata(&metadata)?)
+            .map_err(StoreError::IO)?;
+        Ok(metadata)
+    }
+
+    // picks the single file matching the pattern, falling back to the primary file if there
+    // are several
     pub fn file_for_version(
         &self,
         project: &ProjectReader,
         version: &Version,
+        pattern: Option<&str>,
     ) -> Result<String, StoreError> {
-        let mut version_dir = self.versions_dir(project);
-        version_dir.push(&version.name);
-        let version_contents = read_dir(&version_dir)?;
-        if version_contents.len() != 1 {
-            return Err(StoreError::CorruptedVersion);
+        let metadata = self.version_metadata(project, version)?;
+        let pattern = pattern
+            .map(glob::Pattern::new)
+            .transpose()
+            .map_err(|_| StoreError::InvalidFile)?;
+        let mut files = metadata
+            .files
+            .into_iter()
+            .map(|f| f.name)
+            .filter(|name| pattern.as_ref().is_none_or(|p| p.matches(name)))
+            .collect::<Vec<_>>();
+        match files.len() {
+            0 => Err(StoreError::FileNotFound),
+            1 => Ok(files.remove(0)),
+            _ => metadata
+                .primary
+                .filter(|primary| files.contains(primary))
+                .ok_or(StoreError::MultipleFiles),
         }
-        Ok(version_contents.into_iter().next().unwrap())
     }
 
-    pub fn path_for_version(
+    pub fn open_file(
         &self,
         project: &ProjectReader,
         version: &Version,
-    ) -> Result<PathBuf, StoreError> {
-        let mut path = self.versions_dir(project);
-        let file = self.file_for_version(project, version)?;
-        path.push(&version.name);
-        path.push(&file);
-        Ok(path)
+        file_name: &str,
+        range: Option<&str>,
+    ) -> Result<Object, StoreError> {
+        validate_file_name(file_name)?;
+        let metadata = self.version_metadata(project, version)?;
+        let file = metadata.file(file_name).ok_or(StoreError::FileNotFound)?;
+        let open = |key: &str| match range {
+            Some(range) => self.storage.open_range(key, range),
+            None => self.storage.open(key),
+        };
+        match open(&self.blob_key(&file.sha256)) {
+            // versions uploaded before blobs keep their own copy of each file
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                open(&self.file_key(project, version, file_name)).map_err(StoreError::IO)
+            }
+            result => result.map_err(StoreError::IO),
+        }
+    }
+
+    fn blob_key(&self, sha256: &str) -> String {
+        format!(
+            "{}/sha256/{}/{}",
+            BLOBS_DIR,
+            sha256.get(..2).unwrap_or_default(),
+            sha256
+        )
     }
 
-    pub fn outpath_for(
+    fn blob_reference(&self, project_name: &str, version: &Version, file_name: &str) -> String {
+        format!("{}/{}/{}", project_name, version.name, file_name)
+    }
+
+    // moves each staged file into its blob, unless one with the same contents is already there
+    fn add_blobs(
+        &self,
+        project_name: &str,
+        version: &Version,
+        files_dir: &Path,
+        metadata: &VersionMetadata,
+    ) -> Result<(), StoreError> {
+        let _guard = self.blob_lock.lock().unwrap();
+        for file in &metadata.files {
+            let key = self.blob_key(&file.sha256);
+            let staged = files_dir.join(&file.name);
+            if self.storage.exists(&key).map_err(StoreError::IO)? {
+                // promotions only stage the files that don't have a blob yet
+                match fs::remove_file(&staged) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(StoreError::IO(e)),
+                    _ => {}
+                }
+            } else {
+                self.storage
+                    .put_file(&key, &staged)
+                    .map_err(StoreError::IO)?;
+            }
+            let refs_key = format!("{}.refs", key);
+            let reference = self.blob_reference(project_name, version, &file.name);
+            let mut refs = self.read_lines(&refs_key)?;
+            refs.retain(|r| !r.is_empty());
+            if !refs.contains(&reference) {
+                refs.push(reference);
+                self.storage
+                    .write(&refs_key, &encode_lines(&refs))
+                    .map_err(StoreError::IO)?;
+            }
+        }
+        Ok(())
+    }
+
+    // drops the version's references, deleting blobs that nothing refers to anymore
+    fn release_blobs(
+        &self,
+        project_name: &str,
+        version: &Version,
+        metadata: &VersionMetadata,
+    ) -> Result<(), StoreError> {
+        let _guard = self.blob_lock.lock().unwrap();
+        for file in &metadata.files {
+            let key = self.blob_key(&file.sha256);
+            let refs_key = format!("{}.refs", key);
+            let reference = self.blob_reference(project_name, version, &file.name);
+            let mut refs = self.read_lines(&refs_key)?;
+            refs.retain(|r| !r.is_empty() && *r != reference);
+            if !refs.is_empty() {
+                self.storage
+                    .write(&refs_key, &encode_lines(&refs))
+                    .map_err(StoreError::IO)?;
+                continue;
+            }
+            for key in [&key, &refs_key] {
+                match self.storage.delete(key) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(StoreError::IO(e)),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn reserve_version(
         &self,
         project: &ProjectWriter,
         version: &Version,
-        file_name: &str,
-    ) -> Result<PathBuf, StoreError> {
-        let mut version_path = self.versions_dir(project.reader());
-        version_path.push(&version.name);
-        if fs::exists(&version_path).map_err(StoreError::IO)? {
-            if !fs::read_dir(&version_path)
+    ) -> Result<VersionReservation, StoreError> {
+        let key = (project.name().to_owned(), version.name.clone());
+        let mut reserved_versions = self.reserved_versions.lock().unwrap();
+        if reserved_versions.contains(&(key.0.clone(), WHOLE_PROJECT.to_owned())) {
+            return Err(StoreError::ProjectBusy);
+        }
+        if !reserved_versions.insert(key.clone()) {
+            return Err(StoreError::VersionUploadInProgress);
+        }
+        Ok(VersionReservation {
+            reserved_versions: self.reserved_versions.clone(),
+            key,
+        })
+    }
+
+    // keeps every version of a project from being reserved, failing if one already is
+    fn reserve_project(&self, project_name: &str) -> Result<VersionReservation, StoreError> {
+        let key = (project_name.to_owned(), WHOLE_PROJECT.to_owned());
+        let mut reserved_versions = self.reserved_versions.lock().unwrap();
+        if reserved_versions
+            .iter()
+            .any(|(name, _)| name == project_name)
+        {
+            return Err(StoreError::ProjectBusy);
+        }
+        reserved_versions.insert(key.clone());
+        Ok(VersionReservation {
+            reserved_versions: self.reserved_versions.clone(),
+            key,
+        })
+    }
+
+    pub fn version_exists(&self, project: &ProjectReader, version: &Version) -> bool {
+        if self
+            .storage
+            .exists(&self.metadata_key(project, version))
+            .unwrap_or(false)
+        {
+            return true;
+        }
+        // files without metadata are either from before it was recorded or an unfinished publish
+        self.storage.publishes_atomically()
+            && self
+                .storage
+                .list_files(&self.version_key(project, version))
+                .is_ok_and(|files| !files.is_empty())
+    }
+
+    pub fn publish_version(
+        &self,
+        project: &ProjectWriter,
+        version: &Version,
+        staging_dir: &Path,
+        metadata: &VersionMetadata,
+    ) -> Result<(), StoreError> {
+        let files_dir = staging_dir.join("files");
+        // a failed publish leaves its references behind rather than risk dropping those of a
+        // version that beat it, so at worst a blob is kept around for nothing
+        self.add_blobs(project.name(), version, &files_dir, metadata)?;
+        fs::remove_dir(&files_dir).map_err(StoreError::IO)?;
+        fs::write(staging_dir.join(METADATA_FILE), encode_metadata(metadata)?)
+            .map_err(StoreError::IO)?;
+        self.storage
+            .publish(
+                staging_dir,
+                &self.version_key(project.reader(), version),
+                METADATA_FILE,
+            )
+            .map_err(rename_error)
+    }
+
+    // adds a version of one project to another, sharing the source's blobs rather than copying
+    pub fn promote_version(
+        &self,
+        source: &ProjectReader,
+        version: &Version,
+        destination: &ProjectWriter,
+    ) -> Result<VersionMetadata, StoreError> {
+        let source_metadata = self.version_metadata(source, version)?;
+        let _reservation = self.reserve_version(destination, version)?;
+        if self.version_exists(destination.reader(), version) {
+            return Err(StoreError::VersionExists);
+        }
+        let metadata = VersionMetadata {
+            uploaded_at: unix_time(SystemTime::now()),
+            uploader: Some(destination.identity().name().to_owned()),
+            yanked: None,
+            promoted_from: Some(Promotion {
+                project: source.name.clone(),
+                version: version.name.clone(),
+            }),
+            ..source_metadata
+        };
+        let staging_dir = self.create_staging_dir()?;
+        let result = self
+            .stage_missing_blobs(source, version, &staging_dir, &metadata)
+            .and_then(|()| self.publish_version(destination, version, &staging_dir, &metadata));
+        if let Err(e) = result {
+            let _ = fs::remove_dir_all(&staging_dir);
+            return Err(e);
+        }
+        event!(
+            Level::INFO,
+            "{} promoted version {} from project {} to project {}",
+            destination.identity().name(),
+            version.name,
+            source.name,
+            destination.name()
+        );
+        Ok(metadata)
+    }
+
+    // versions uploaded before blobs have their files copied into blobs on the way
+    fn stage_missing_blobs(
+        &self,
+        source: &ProjectReader,
+        version: &Version,
+        staging_dir: &Path,
+        metadata: &VersionMetadata,
+    ) -> Result<(), StoreError> {
+        for file in &metadata.files {
+            if self
+                .storage
+                .exists(&self.blob_key(&file.sha256))
                 .map_err(StoreError::IO)?
-                .next()
-                .is_none()
             {
-                return Err(StoreError::Other("version already exists".to_string()));
+                continue;
             }
+            let mut reader = self
+                .storage
+                .open(&self.file_key(source, version, &file.name))
+                .and_then(Object::into_reader)
+                .map_err(StoreError::IO)?;
+            let mut staged = fs::File::create_new(staging_dir.join("files").join(&file.name))
+                .map_err(StoreError::IO)?;
+            io::copy(&mut reader, &mut staged).map_err(StoreError::IO)?;
+        }
+        Ok(())
+    }
+
+    // the checks delete_version makes before deleting anything
+    pub fn check_deletable(
+        &self,
+        project: &ProjectDeleter,
+        version: &Version,
+    ) -> Result<(), StoreError> {
+        let reader = project.writer().reader();
+        if !self
+            .storage
+            .is_dir(&self.version_key(reader, version))
+            .map_err(StoreError::IO)?
+        {
+            return Err(StoreError::VersionNotFound);
+        }
+        // channels have to be moved off a version before it can go
+        match self
+            .list_channels(reader)?
+            .into_iter()
+            .find(|(_, v)| *v == version.name)
+        {
+            Some((channel, _)) => Err(StoreError::VersionInChannel(channel)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn delete_version(
+        &self,
+        project: &ProjectDeleter,
+        version: &Version,
+    ) -> Result<(), StoreError> {
+        let _reservation = self.reserve_version(project.writer(), version)?;
+        let _guard = self.channels_lock.lock().unwrap();
+        self.check_deletable(project, version)?;
+        let reader = project.writer().reader();
+        let key = self.version_key(reader, version);
+        let metadata = self.version_metadata(reader, version).ok();
+        self.storage.delete(&key).map_err(StoreError::IO)?;
+        if let Some(metadata) = metadata {
+            self.release_blobs(project.name(), version, &metadata)?;
+        }
+        event!(
+            Level::INFO,
+            "{} deleted version {} of project {}",
+            project.identity().name(),
+            version.name,
+            project.name()
+        );
+        Ok(())
+    }
+
+    pub fn yank_version(
+        &self,
+        project: &ProjectWriter,
+        version: &Version,
+        reason: Option<String>,
+    ) -> Result<VersionMetadata, StoreError> {
+        let yank = Yank {
+            yanked_at: unix_time(SystemTime::now()),
+            yanked_by: Some(project.identity().name().to_owned()),
+            reason,
+        };
+        let metadata = self.update_metadata(project, version, |metadata| {
+            metadata.yanked = Some(yank);
+        })?;
+        event!(
+            Level::INFO,
+            "{} yanked version {} of project {}",
+            project.identity().name(),
+            version.name,
+            project.name()
+        );
+        Ok(metadata)
+    }
+
+    pub fn unyank_version(
+        &self,
+        project: &ProjectWriter,
+        version: &Version,
+    ) -> Result<VersionMetadata, StoreError> {
+        let metadata = self.update_metadata(project, version, |metadata| {
+            metadata.yanked = None;
+        })?;
+        event!(
+            Level::INFO,
+            "{} unyanked version {} of project {}",
+            project.identity().name(),
+            version.name,
+            project.name()
+        );
+        Ok(metadata)
+    }
+
+    fn update_metadata(
+        &self,
+        project: &ProjectWriter,
+        version: &Version,
+        update: impl FnOnce(&mut VersionMetadata),
+    ) -> Result<VersionMetadata, StoreError> {
+        let _guard = self.metadata_lock.lock().unwrap();
+        let mut metadata = self.version_metadata(project.reader(), version)?;
+        update(&mut metadata);
+        self.storage
+            .write(
+                &self.metadata_key(project.reader(), version),
+                &encode_metadata(&metadata)?,
+            )
+            .map_err(StoreError::IO)?;
+        Ok(metadata)
+    }
+
+    fn channel_key(&self, project: &ProjectReader, channel: &str) -> Result<String, StoreError> {
+        if !valid_name(channel) {
+            return Err(StoreError::InvalidChannel);
+        }
+        Ok(format!(
+            "{}/{}/{}.json",
+            project.name, CHANNELS_DIR, channel
+        ))
+    }
+
+    // maps each channel to the version it points at
+    pub fn list_channels(
+        &self,
+        project: &ProjectReader,
+    ) -> Result<BTreeMap<String, String>, StoreError> {
+        let files = self
+            .storage
+            .list_files(&format!("{}/{}", project.name, CHANNELS_DIR))
+            .map_err(StoreError::IO)?;
+        let mut channels = BTreeMap::new();
+        for name in files.iter().filter_map(|f| f.strip_suffix(".json")) {
+            if let Ok(channel) = self.channel(project, name) {
+                channels.insert(name.to_owned(), channel.version);
+            }
+        }
+        Ok(channels)
+    }
+
+    pub fn channel(&self, project: &ProjectReader, name: &str) -> Result<Channel, StoreError> {
+        self.read_json(&self.channel_key(project, name)?)?
+            .ok_or(StoreError::ChannelNotFound)
+    }
+
+    pub fn resolve_channel(
+        &self,
+        project: &ProjectReader,
+        name: &str,
+    ) -> Result<Version, StoreError> {
+        Version::new(self.channel(project, name)?.version)
+    }
+
+    // points the channel at an existing version, creating the channel if needed
+    pub fn move_channel(
+        &self,
+        project: &ProjectWriter,
+        name: &str,
+        version: &Version,
+    ) -> Result<Channel, StoreError> {
+        let key = self.channel_key(project.reader(), name)?;
+        let _guard = self.channels_lock.lock().unwrap();
+        if !self.version_exists(project.reader(), version) {
+            return Err(StoreError::VersionNotFound);
+        }
+        let mut channel = self.read_json(&key)?.unwrap_or(Channel {
+            version: String::new(),
+            history: Vec::new(),
+        });
+        channel.version = version.name.clone();
+        channel.history.push(ChannelMove {
+            version: version.name.clone(),
+            moved_at: unix_time(SystemTime::now()),
+            moved_by: Some(project.identity().name().to_owned()),
+        });
+        let contents = serde_json::to_vec_pretty(&channel).map_err(|e| StoreError::IO(e.into()))?;
+        self.storage
+            .write(&key, &contents)
+            .map_err(StoreError::IO)?;
+        event!(
+            Level::INFO,
+            "{} moved channel {} of project {} to version {}",
+            project.identity().name(),
+            name,
+            project.name(),
+            version.name
+        );
+        Ok(channel)
+    }
+
+    // returns the (project, version) pairs that were deleted, or would be with dry_run
+    pub fn collect_garbage(&self, dry_run: bool) -> Result<Vec<(String, String)>, StoreError> {
+        let now = unix_time(SystemTime::now());
+        let mut collected = Vec::new();
+        for name in self.list_projects()? {
+            let project = ProjectDeleter {
+                writer: ProjectWriter {
+                    reader: ProjectReader {
+                        name,
+                        identity: Identity::internal("retention-policy"),
+                    },
+                },
+            };
+            // one broken project shouldn't stop retention for all the others
+            let expired = match self.expired_versions(project.writer().reader(), now) {
+                Ok(expired) => expired,
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "skipping retention policy of project {}: {}",
+                        project.name(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            for version in expired {
+                if !dry_run {
+                    if let Err(e) = self.delete_version(&project, &version) {
+                        event!(
+                            Level::WARN,
+                            "failed to delete expired version {} of project {}: {}",
+                            version.name,
+                            project.name(),
+                            e
+                        );
+                        continue;
+                    }
+                }
+                collected.push((project.name().to_owned(), version.name));
+            }
+        }
+        Ok(collected)
+    }
+
+    fn expired_versions(
+        &self,
+        project: &ProjectReader,
+        now: u64,
+    ) -> Result<Vec<Version>, StoreError> {
+        let config = self.project_config(&Project {
+            name: project.name.clone(),
+        })?;
+        let Some(retention) = config.retention else {
+            return Ok(Vec::new());
+        };
+        let versions = self.sorted_versions(project)?;
+        let channels = self.list_channels(project)?;
+        let expired = retention
+            .expired(&versions, now)
+            .map_err(|e| StoreError::InvalidConfig(e.to_string()))?;
+        // versions that a channel points at are kept regardless of the policy
+        Ok(expired
+            .into_iter()
+            .filter(|v| !channels.values().any(|c| *c == v.name))
+            .map(|v| Version {
+                name: v.name.clone(),
+            })
+            .collect())
+    }
+}
+
+impl Store {
+    pub fn admin(&self, headers: &HeaderMap) -> Result<Admin, StoreError> {
+        let cred = Credential::from_headers(headers)?;
+        let identity = self.identify(&cred)?;
+        if !self.is_admin(&cred, &identity)? {
+            event!(Level::INFO, "denied {} admin access", identity.name());
+            return Err(StoreError::UnauthorizedAdmin);
+        }
+        Ok(Admin { identity })
+    }
+
+    pub fn create_project(
+        &self,
+        admin: &Admin,
+        project_name: String,
+        config: &ProjectConfig,
+    ) -> Result<(), StoreError> {
+        let project = Project::new(project_name)?;
+        if self.project_key(&project).is_ok() {
+            return Err(StoreError::ProjectExists);
+        }
+        let config = encode_config(config)?;
+        let staging_dir = self.staging_path("project")?;
+        fs::create_dir(&staging_dir).map_err(StoreError::IO)?;
+        let result = (|| {
+            for role in [AclRole::Readers, AclRole::Writers] {
+                fs::write(staging_dir.join(role.file_name()), b"").map_err(StoreError::IO)?;
+            }
+            fs::write(staging_dir.join(PROJECT_CONFIG_FILE), &config).map_err(StoreError::IO)?;
+            self.storage
+                .publish(&staging_dir, &project.name, PROJECT_CONFIG_FILE)
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::AlreadyExists => StoreError::ProjectExists,
+                    _ => StoreError::IO(e),
+                })
+        })();
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&staging_dir);
+        }
+        result?;
+        event!(
+            Level::INFO,
+            "{} created project {}",
+            admin.identity().name(),
+            project.name
+        );
+        Ok(())
+    }
+
+    pub fn delete_project(&self, admin: &Admin, project_name: String) -> Result<(), StoreError> {
+        let project = Project::new(project_name)?;
+        let key = self.project_key(&project)?;
+        // an upload that finished after the delete would bring the project back without its ACLs
+        let _reservation = self.reserve_project(&project.name)?;
+        let reader = ProjectReader {
+            name: project.name,
+            identity: admin.identity.clone(),
+        };
+        let versions = self.sorted_versions(&reader)?;
+        self.storage.delete(&key).map_err(StoreError::IO)?;
+        for (version, metadata) in versions {
+            self.release_blobs(&reader.name, &version, &metadata)?;
+        }
+        event!(
+            Level::INFO,
+            "{} deleted project {}",
+            admin.identity().name(),
+            reader.name
+        );
+        Ok(())
+    }
+
+    pub fn project_acl(
+        &self,
+        _admin: &Admin,
+        project_name: String,
+    ) -> Result<ProjectAcl, StoreError> {
+        let project = Project::new(project_name)?;
+        let key = self.project_key(&project)?;
+        let read_list = |role: AclRole| -> Result<Vec<String>, StoreError> {
+            Ok(self
+                .read_lines(&self.acl_key(&key, role))?
+                .into_iter()
+                .filter(|l| !l.is_empty())
+                .collect())
+        };
+        Ok(ProjectAcl {
+            config: self.project_config(&project)?,
+            readers: read_list(AclRole::Readers)?,
+            writers: read_list(AclRole::Writers)?,
+        })
+    }
+
+    pub fn set_project_config(
+        &self,
+        admin: &Admin,
+        project_name: String,
+        config: &ProjectConfig,
+    ) -> Result<(), StoreError> {
+        let project = Project::new(project_name)?;
+        let key = format!("{}/{}", self.project_key(&project)?, PROJECT_CONFIG_FILE);
+        let config = encode_config(config)?;
+        let _guard = self.acl_lock.lock().unwrap();
+        self.storage.write(&key, &config).map_err(StoreError::IO)?;
+        event!(
+            Level::INFO,
+            "{} updated the configuration of project {}",
+            admin.identity().name(),
+            project.name
+        );
+        Ok(())
+    }
+
+    pub fn add_acl_entry(
+        &self,
+        admin: &Admin,
+        project_name: String,
+        role: AclRole,
+        entry: &AclEntry,
+    ) -> Result<(), StoreError> {
+        let project = Project::new(project_name)?;
+        let key = self.acl_key(&self.project_key(&project)?, role);
+        let line = entry.line().ok_or(StoreError::InvalidAclEntry)?;
+        let _guard = self.acl_lock.lock().unwrap();
+        let mut lines = self.read_lines(&key)?;
+        lines.retain(|l| !l.is_empty());
+        lines.push(line);
+        self.storage
+            .write(&key, &encode_lines(&lines))
+            .map_err(StoreError::IO)?;
+        event!(
+            Level::INFO,
+            "{} added {} to {} of project {}",
+            admin.identity().name(),
+            entry.describe(&self.fingerprint_key()?),
+            role.file_name(),
+            project.name
+        );
+        Ok(())
+    }
+
+    pub fn remove_acl_entry(
+        &self,
+        admin: &Admin,
+        project_name: String,
+        role: AclRole,
+        entry: &AclEntry,
+    ) -> Result<usize, StoreError> {
+        let project = Project::new(project_name)?;
+        let key = self.acl_key(&self.project_key(&project)?, role);
+        entry.line().ok_or(StoreError::InvalidAclEntry)?;
+        let _guard = self.acl_lock.lock().unwrap();
+        let mut lines = self.read_lines(&key)?;
+        let before = lines.len();
+        lines.retain(|l| !entry.matches(l));
+        let removed = before - lines.len();
+        if removed > 0 {
+            self.storage
+                .write(&key, &encode_lines(&lines))
+                .map_err(StoreError::IO)?;
+            event!(
+                Level::INFO,
+                "{} removed {} from {} of project {}",
+                admin.identity().name(),
+                entry.describe(&self.fingerprint_key()?),
+                role.file_name(),
+                project.name
+            );
         }
-        fs::create_dir(&version_path).map_err(StoreError::IO)?;
-        version_path.push(file_name);
-        Ok(version_path)
+        Ok(removed)
     }
 }
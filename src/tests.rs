@@ -0,0 +1,1650 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+use axum::body::{self, Body, Bytes};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Request, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use sha2::Digest;
+use tempfile::TempDir;
+use tower::ServiceExt;
+
+use crate::s3::S3;
+use crate::storage::Filesystem;
+use crate::store::Store;
+use crate::token::{self, Identity, Scope, TokenRecord};
+use crate::{parse_duration, parse_size, router};
+
+const TOKEN: &str = "test-token";
+const BOUNDARY: &str = "artifacts-r-us-boundary";
+
+fn setup(project: &str) -> (TempDir, Router) {
+    setup_with_limit(project, None)
+}
+
+fn setup_with_limit(project: &str, max_upload_size: Option<u64>) -> (TempDir, Router) {
+    let dir = tempfile::tempdir().unwrap();
+    let project_dir = dir.path().join(project);
+    fs::create_dir(&project_dir).unwrap();
+    fs::write(project_dir.join("readers.txt"), format!("{}\n", TOKEN)).unwrap();
+    fs::write(project_dir.join("writers.txt"), format!("{}\n", TOKEN)).unwrap();
+    let store = open_store(&dir, max_upload_size);
+    (dir, router(Arc::new(store)))
+}
+
+fn open_store(dir: &TempDir, max_upload_size: Option<u64>) -> Store {
+    Store::new(
+        dir.path().to_str().unwrap().to_owned(),
+        Box::new(Filesystem::new(dir.path().to_owned())),
+        max_upload_size,
+    )
+}
+
+type Bucket = Arc<Mutex<BTreeMap<String, Bytes>>>;
+
+// just enough of the S3 API for the store, keeping objects in memory
+fn s3_stand_in() -> (String, Bucket) {
+    let bucket = Bucket::default();
+    let app = Router::new()
+        .route("/{bucket}", get(s3_list))
+        .route(
+            "/{bucket}/{*key}",
+            get(s3_get).put(s3_put).delete(s3_delete),
+        )
+        .with_state(bucket.clone());
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    listener.set_nonblocking(true).unwrap();
+    std::thread::spawn(move || {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                axum::serve(listener, app).await.unwrap();
+            })
+    });
+    (endpoint, bucket)
+}
+
+fn s3_signed(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("AWS4-HMAC-SHA256 Credential=test-key/"))
+        && headers.contains_key("x-amz-date")
+        && headers.contains_key("x-amz-content-sha256")
+}
+
+async fn s3_list(
+    State(bucket): State<Bucket>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !s3_signed(&headers) {
+        return (StatusCode::FORBIDDEN, String::new());
+    }
+    let prefix = params.get("prefix").cloned().unwrap_or_default();
+    let delimiter = params.contains_key("delimiter");
+    let mut keys = Vec::new();
+    let mut prefixes = BTreeSet::new();
+    for key in bucket.lock().unwrap().keys() {
+        let Some(rest) = key.strip_prefix(&prefix) else {
+            continue;
+        };
+        match rest.find('/') {
+            Some(i) if delimiter => {
+                prefixes.insert(format!("{}{}", prefix, &rest[..=i]));
+            }
+            _ => keys.push(key.clone()),
+        }
+    }
+    let mut xml = String::from("<ListBucketResult><IsTruncated>false</IsTruncated>");
+    for key in keys {
+        xml.push_str(&format!("<Contents><Key>{}</Key></Contents>", key));
+    }
+    for prefix in prefixes {
+        xml.push_str(&format!(
+            "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+            prefix
+        ));
+    }
+    xml.push_str("</ListBucketResult>");
+    (StatusCode::OK, xml)
+}
+
+async fn s3_get(
+    State(bucket): State<Bucket>,
+    Path((_, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if !s3_signed(&headers) {
+        return (StatusCode::FORBIDDEN, HeaderMap::new(), Bytes::new());
+    }
+    let Some(contents) = bucket.lock().unwrap().get(&key).cloned() else {
+        return (StatusCode::NOT_FOUND, HeaderMap::new(), Bytes::new());
+    };
+    // only single ranges with a start, which is all the tests ask for
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok()?.strip_prefix("bytes=")?.split_once('-'))
+        .and_then(|(start, end)| {
+            let start = start.parse::<usize>().ok()?;
+            let end = end.parse::<usize>().unwrap_or(usize::MAX);
+            Some((start, end.min(contents.len().saturating_sub(1))))
+        });
+    match range {
+        Some((start, _)) if start >= contents.len() => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            HeaderMap::new(),
+            Bytes::new(),
+        ),
+        Some((start, end)) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, contents.len()))
+                    .unwrap(),
+            );
+            (
+                StatusCode::PARTIAL_CONTENT,
+                headers,
+                contents.slice(start..=end),
+            )
+        }
+        None => (StatusCode::OK, HeaderMap::new(), contents),
+    }
+}
+
+async fn s3_put(
+    State(bucket): State<Bucket>,
+    Path((_, key)): Path<(String, String)>,
+    headers: HeaderMap,
+    contents: Bytes,
+) -> StatusCode {
+    if !s3_signed(&headers) {
+        return StatusCode::FORBIDDEN;
+    }
+    let mut bucket = bucket.lock().unwrap();
+    if headers.get(header::IF_NONE_MATCH).is_some_and(|v| v == "*") && bucket.contains_key(&key) {
+        return StatusCode::PRECONDITION_FAILED;
+    }
+    bucket.insert(key, contents);
+    StatusCode::OK
+}
+
+async fn s3_delete(
+    State(bucket): State<Bucket>,
+    Path((_, key)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> StatusCode {
+    if !s3_signed(&headers) {
+        return StatusCode::FORBIDDEN;
+    }
+    bucket.lock().unwrap().remove(&key);
+    StatusCode::NO_CONTENT
+}
+
+fn get_request(uri: &str) -> Request<Body> {
+    Request::get(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn with_token(mut request: Request<Body>, token: &str) -> Request<Body> {
+    request.headers_mut().insert(
+        header::AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+    );
+    request
+}
+
+async fn error_code(response: axum::response::Response) -> String {
+    let body: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+    body["error"].as_str().unwrap().to_owned()
+}
+
+async fn body_string(response: axum::response::Response) -> String {
+    let bytes = body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+fn multipart_body(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, contents) in files {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n",
+                BOUNDARY, name
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(contents);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+    body
+}
+
+fn upload_request(project: &str, version: &str, files: &[(&str, &[u8])]) -> Request<Body> {
+    Request::post(format!("/project/{}/upload?version={}", project, version))
+        .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={}", BOUNDARY),
+        )
+        .body(Body::from(multipart_body(files)))
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn concurrent_uploads_of_same_version() {
+    let (dir, app) = setup("demo");
+    for round in 0..10 {
+        let version = format!("1.0.{}", round);
+        let uploads = (0..16).map(|i| {
+            let app = app.clone();
+            let version = version.clone();
+            tokio::spawn(async move {
+                let contents = format!("upload {}", i).repeat(4096);
+                let response = app
+                    .oneshot(upload_request(
+                        "demo",
+                        &version,
+                        &[("artifact.bin", contents.as_bytes())],
+                    ))
+                    .await
+                    .unwrap();
+                (response.status(), contents)
+            })
+        });
+        let mut winners = Vec::new();
+        for upload in uploads {
+            let (status, contents) = upload.await.unwrap();
+            match status {
+                StatusCode::OK => winners.push(contents),
+                StatusCode::CONFLICT => {}
+                status => panic!("unexpected status {}", status),
+            }
+        }
+        assert_eq!(winners.len(), 1, "round {}", round);
+        let response = app
+            .clone()
+            .oneshot(get_request(&format!(
+                "/project/demo/version/{}/file/artifact.bin",
+                version
+            )))
+            .await
+            .unwrap();
+        assert_eq!(body_string(response).await, winners[0]);
+    }
+    assert!(fs::read_dir(dir.path().join(".tmp"))
+        .unwrap()
+        .next()
+        .is_none());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn concurrent_uploads_over_abandoned_version() {
+    let (dir, app) = setup("demo");
+    fs::create_dir_all(dir.path().join("demo/versions/2.0.0")).unwrap();
+    let uploads = (0..16).map(|i| {
+        let app = app.clone();
+        tokio::spawn(async move {
+            let contents = format!("upload {}", i);
+            app.oneshot(upload_request(
+                "demo",
+                "2.0.0",
+                &[("a", contents.as_bytes())],
+            ))
+            .await
+            .unwrap()
+            .status()
+        })
+    });
+    let mut successes = 0;
+    for upload in uploads {
+        match upload.await.unwrap() {
+            StatusCode::OK => successes += 1,
+            StatusCode::CONFLICT => {}
+            status => panic!("unexpected status {}", status),
+        }
+    }
+    assert_eq!(successes, 1);
+    let response = app
+        .oneshot(get_request("/project/demo/version/2.0.0/files"))
+        .await
+        .unwrap();
+    assert_eq!(body_string(response).await, r#"["a"]"#);
+    assert!(dir
+        .path()
+        .join("demo/versions/2.0.0/version.json")
+        .is_file());
+}
+
+#[tokio::test]
+async fn identical_files_are_stored_once() {
+    let (dir, app) = setup("demo");
+    fs::write(dir.path().join("admins.txt"), format!("{}\n", TOKEN)).unwrap();
+    for version in ["1.0.0", "1.0.1"] {
+        let response = app
+            .clone()
+            .oneshot(upload_request(
+                "demo",
+                version,
+                &[("app.bin", b"same bytes")],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let blob = dir
+        .path()
+        .join("blobs/sha256/58/58100dc8fc06562ce3e578231dc948e083520ee49c4b4ee5a5a28bb4b4003feb");
+    let refs = blob.with_extension("refs");
+    assert_eq!(fs::read_to_string(&blob).unwrap(), "same bytes");
+    assert_eq!(fs::read_to_string(&refs).unwrap().lines().count(), 2);
+    assert_eq!(
+        fs::read_dir(dir.path().join("demo/versions/1.0.0"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>(),
+        ["version.json"]
+    );
+
+    let delete = |version: &str| {
+        Request::delete(format!("/project/demo/version/{}", version))
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = app.clone().oneshot(delete("1.0.0")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(fs::read_to_string(&refs).unwrap(), "demo/1.0.1/app.bin\n");
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/demo/version/1.0.1/file/app.bin"))
+        .await
+        .unwrap();
+    assert_eq!(body_string(response).await, "same bytes");
+
+    let response = app.oneshot(delete("1.0.1")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(!blob.exists());
+    assert!(!refs.exists());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn uploads_and_downloads_through_s3() {
+    let (endpoint, bucket) = s3_stand_in();
+    for list in ["readers.txt", "writers.txt"] {
+        bucket.lock().unwrap().insert(
+            format!("artifacts/demo/{}", list),
+            Bytes::from(format!("{}\n", TOKEN)),
+        );
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let s3 = S3::new(
+        endpoint,
+        "bucket".to_owned(),
+        "artifacts".to_owned(),
+        "us-east-1".to_owned(),
+        "test-key".to_owned(),
+        "test-secret".to_owned(),
+    );
+    let store = Store::new(dir.path().to_str().unwrap().to_owned(), Box::new(s3), None);
+    let app = router(Arc::new(store));
+
+    let files: &[(&str, &[u8])] = &[("app.tar.gz", b"archive"), ("notes+1.txt", b"notes")];
+    let response = app
+        .clone()
+        .oneshot(upload_request("demo", "1.0.0", files))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(bucket
+        .lock()
+        .unwrap()
+        .contains_key("artifacts/demo/versions/1.0.0/version.json"));
+    assert!(fs::read_dir(dir.path().join(".tmp"))
+        .unwrap()
+        .next()
+        .is_none());
+
+    let response = app
+        .clone()
+        .oneshot(upload_request("demo", "1.0.0", files))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // files without version.json are an unfinished publish, not a version
+    bucket.lock().unwrap().insert(
+        "artifacts/demo/versions/0.9.0/app.tar.gz".to_owned(),
+        Bytes::from_static(b"partial"),
+    );
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/demo/versions"))
+        .await
+        .unwrap();
+    assert_eq!(body_string(response).await, r#"["1.0.0"]"#);
+
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/demo/version/latest/files"))
+        .await
+        .unwrap();
+    assert_eq!(
+        body_string(response).await,
+        r#"["app.tar.gz","notes+1.txt"]"#
+    );
+
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/demo/version/1.0.0/file/notes+1.txt"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_LENGTH], "5");
+    assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+    assert_eq!(body_string(response).await, "notes");
+
+    // ranges are passed on to S3 rather than read past
+    let ranged = |range: &str, if_range: Option<&str>| {
+        let mut request = get_request("/project/demo/version/1.0.0/file/app.tar.gz");
+        let headers = request.headers_mut();
+        headers.insert(header::RANGE, HeaderValue::from_str(range).unwrap());
+        if let Some(if_range) = if_range {
+            headers.insert(header::IF_RANGE, HeaderValue::from_str(if_range).unwrap());
+        }
+        request
+    };
+    let response = app
+        .clone()
+        .oneshot(ranged("bytes=2-4", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 2-4/7");
+    assert_eq!(response.headers()[header::CONTENT_LENGTH], "3");
+    let etag = response.headers()[header::ETAG]
+        .to_str()
+        .unwrap()
+        .to_owned();
+    assert_eq!(body_string(response).await, "chi");
+    let response = app
+        .clone()
+        .oneshot(ranged("bytes=4-", Some(&etag)))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(body_string(response).await, "ive");
+    let response = app
+        .clone()
+        .oneshot(ranged("bytes=4-", Some("\"something-else\"")))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_string(response).await, "archive");
+    let response = app
+        .clone()
+        .oneshot(ranged("bytes=100-", None))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */7");
+    assert!(!response.headers().contains_key(header::ETAG));
+
+    let response = app
+        .oneshot(get_request("/project/demo/version/0.9.0/file/app.tar.gz"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn channels_follow_moves() {
+    let (dir, app) = setup("demo");
+    for version in ["1.0.0", "1.1.0"] {
+        let response = app
+            .clone()
+            .oneshot(upload_request(
+                "demo",
+                version,
+                &[("app.bin", version.as_bytes())],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let move_to = |version: &str| {
+        Request::put("/project/demo/channel/stable")
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(r#"{{"version": "{}"}}"#, version)))
+            .unwrap()
+    };
+    let response = app.clone().oneshot(move_to("2.0.0")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    for (version, expected) in [("latest", "1.1.0"), ("1.0.0", "1.0.0")] {
+        let response = app.clone().oneshot(move_to(version)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .clone()
+            .oneshot(get_request("/project/demo/channel/stable/download"))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            format!("/project/demo/version/{}/file/app.bin", expected)
+        );
+    }
+
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/demo/channel/stable"))
+        .await
+        .unwrap();
+    let channel: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+    assert_eq!(channel["version"], "1.0.0");
+    let history = channel["history"].as_array().unwrap();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0]["version"], "1.1.0");
+
+    fs::write(dir.path().join("admins.txt"), format!("{}\n", TOKEN)).unwrap();
+    let delete = |version: &str| {
+        Request::delete(format!("/project/demo/version/{}", version))
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = app.clone().oneshot(delete("1.0.0")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert!(body_string(response).await.contains("version_in_channel"));
+    let response = app.oneshot(delete("1.1.0")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+}
+
+#[tokio::test]
+async fn promotion_shares_blobs() {
+    let (dir, app) = setup("build");
+    fs::write(dir.path().join("admins.txt"), format!("{}\n", TOKEN)).unwrap();
+    fs::create_dir(dir.path().join("release")).unwrap();
+    let response = app
+        .clone()
+        .oneshot(upload_request(
+            "build",
+            "1.0.0",
+            &[("app.bin", b"build output")],
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let promote = || {
+        Request::post("/project/release/promote")
+            .header(header::AUTHORIZATION, format!("Bearer {}", TOKEN))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                r#"{"from": "build", "version": "latest", "move": true}"#,
+            ))
+            .unwrap()
+    };
+    let response = app.clone().oneshot(promote()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let metadata: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+    assert_eq!(metadata["promoted_from"]["project"], "build");
+
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/release/version/1.0.0/file/app.bin"))
+        .await
+        .unwrap();
+    assert_eq!(body_string(response).await, "build output");
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/build/version/1.0.0"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let refs = fs::read_dir(dir.path().join("blobs/sha256"))
+        .unwrap()
+        .flat_map(|d| fs::read_dir(d.unwrap().path()).unwrap())
+        .map(|f| f.unwrap().path())
+        .find(|p| p.extension().is_some_and(|e| e == "refs"))
+        .unwrap();
+    assert_eq!(fs::read_to_string(refs).unwrap(), "release/1.0.0/app.bin\n");
+}
+
+#[tokio::test]
+async fn downloads_resume_and_revalidate() {
+    let (_dir, app) = setup("demo");
+    let response = app
+        .clone()
+        .oneshot(upload_request(
+            "demo",
+            "1.0.0",
+            &[("app bin", b"0123456789")],
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let download = |version: &str, header: Option<(HeaderName, &str)>| {
+        let mut request = get_request(&format!("/project/demo/version/{}/file/app%20bin", version));
+        if let Some((name, value)) = header {
+            request
+                .headers_mut()
+                .insert(name, HeaderValue::from_str(value).unwrap());
+        }
+        request
+    };
+
+    let response = app.clone().oneshot(download("1.0.0", None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    let etag = headers[header::ETAG].to_str().unwrap().to_owned();
+    assert_eq!(
+        etag,
+        "\"84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882\""
+    );
+    assert_eq!(
+        headers[header::CACHE_CONTROL],
+        "max-age=31536000, immutable"
+    );
+    assert_eq!(
+        headers[header::CONTENT_DISPOSITION],
+        "attachment; filename=\"app bin\""
+    );
+
+    let response = app
+        .clone()
+        .oneshot(download("1.0.0", Some((header::RANGE, "bytes=4-"))))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 4-9/10");
+    assert_eq!(response.headers()[header::ETAG], etag.as_str());
+    assert_eq!(body_string(response).await, "456789");
+
+    let response = app
+        .clone()
+        .oneshot(download("1.0.0", Some((header::RANGE, "bytes=50-60"))))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert!(!response.headers().contains_key(header::CACHE_CONTROL));
+    assert!(!response.headers().contains_key(header::ETAG));
+
+    for version in ["1.0.0", "latest"] {
+        let response = app
+            .clone()
+            .oneshot(download(version, Some((header::IF_NONE_MATCH, &etag))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        assert_eq!(body_string(response).await, "");
+    }
+
+    let response = app
+        .clone()
+        .oneshot(download(
+            "latest",
+            Some((header::IF_NONE_MATCH, "\"other\"")),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=60");
+    assert_eq!(body_string(response).await, "0123456789");
+}
+
+#[tokio::test]
+async fn downloads_pick_a_file() {
+    let (_dir, app) = setup("demo");
+    let files: &[(&str, &[u8])] = &[
+        ("app.tar.gz", b"archive"),
+        ("app.zip", b"zip"),
+        ("checksums.txt", b"sums"),
+    ];
+    for (version, query) in [("1.0.0", ""), ("1.1.0", "&primary=app.zip")] {
+        let mut request = upload_request("demo", version, files);
+        *request.uri_mut() = format!("/project/demo/upload?version={}{}", version, query)
+            .parse()
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    for (uri, status, target) in [
+        ("/project/demo/version/1.0.0/download", 400, None),
+        (
+            "/project/demo/version/1.1.0/download",
+            303,
+            Some("1.1.0/file/app.zip"),
+        ),
+        (
+            "/project/demo/version/1.0.0/download?file=*.tar.gz",
+            303,
+            Some("1.0.0/file/app.tar.gz"),
+        ),
+        ("/project/demo/version/1.0.0/download?file=app.*", 400, None),
+        (
+            "/project/demo/version/1.1.0/download?file=app.*",
+            303,
+            Some("1.1.0/file/app.zip"),
+        ),
+        ("/project/demo/version/1.0.0/download?file=*.deb", 404, None),
+    ] {
+        let response = app.clone().oneshot(get_request(uri)).await.unwrap();
+        assert_eq!(response.status(), status, "{}", uri);
+        if let Some(target) = target {
+            assert_eq!(
+                response.headers()[header::LOCATION],
+                format!("/project/demo/version/{}", target)
+            );
+        }
+    }
+
+    let response = app
+        .oneshot(get_request(
+            "/project/demo/version/latest/download?direct=true&file=*.txt",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=60");
+    assert_eq!(body_string(response).await, "sums");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn versions_download_as_archives() {
+    let (_dir, app) = setup("demo");
+    let large = "0123456789abcdef".repeat(16 * 1024);
+    let files: &[(&str, &[u8])] = &[("app.bin", large.as_bytes()), ("notes.txt", b"notes")];
+    let response = app
+        .clone()
+        .oneshot(upload_request("demo", "1.0.0", files))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let download = |uri: &'static str| {
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(get_request(uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let disposition = response.headers()[header::CONTENT_DISPOSITION].clone();
+            let bytes = body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (disposition, bytes)
+        }
+    };
+
+    let (disposition, bytes) = download("/project/demo/version/latest/archive.tar.gz").await;
+    assert_eq!(disposition, "attachment; filename=\"demo-1.0.0.tar.gz\"");
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(&bytes[..]));
+    let mut entries = Vec::new();
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents).unwrap();
+        entries.push((entry.path().unwrap().display().to_string(), contents));
+    }
+    assert_eq!(
+        entries,
+        [
+            ("demo-1.0.0/app.bin".to_owned(), large.clone()),
+            ("demo-1.0.0/notes.txt".to_owned(), "notes".to_owned())
+        ]
+    );
+
+    let (disposition, bytes) = download("/project/demo/version/1.0.0/archive.zip").await;
+    assert_eq!(disposition, "attachment; filename=\"demo-1.0.0.zip\"");
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes)).unwrap();
+    let mut contents = String::new();
+    archive
+        .by_name("demo-1.0.0/app.bin")
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, large);
+    assert_eq!(archive.len(), 2);
+}
+
+#[tokio::test]
+async fn metadata_parts_count_toward_the_upload_limit() {
+    let metadata_request = |labels: &str| {
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"metadata\"\r\n\r\n{{\"labels\": {{\"padding\": \"{}\"}}}}\r\n",
+            BOUNDARY, labels
+        )
+        .into_bytes();
+        body.extend_from_slice(&multipart_body(&[("app.bin", b"app")]));
+        let mut request = upload_request("demo", "1.0.0", &[]);
+        *request.body_mut() = Body::from(body);
+        request
+    };
+
+    let (_dir, app) = setup_with_limit("demo", Some(1024));
+    let response = app
+        .clone()
+        .oneshot(metadata_request(&"x".repeat(8 * 1024 * 1024)))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let response = app.oneshot(metadata_request("small")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // without a limit, the metadata part is still capped since it's kept in memory
+    let (_dir, app) = setup("demo");
+    let response = app
+        .oneshot(metadata_request(&"x".repeat(1024 * 1024)))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn missing_projects_look_like_private_ones() {
+    let (dir, app) = setup("demo");
+    let request = |project: &str, token: Option<&str>| {
+        let mut request = Request::get(format!("/project/{}/versions", project));
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        request.body(Body::empty()).unwrap()
+    };
+    for (token, status) in [
+        (None, StatusCode::UNAUTHORIZED),
+        (Some("made-up"), StatusCode::FORBIDDEN),
+    ] {
+        for project in ["demo", "missing"] {
+            let response = app.clone().oneshot(request(project, token)).await.unwrap();
+            assert_eq!(response.status(), status, "{} {:?}", project, token);
+        }
+    }
+
+    fs::write(dir.path().join("admins.txt"), format!("{}\n", TOKEN)).unwrap();
+    let response = app.oneshot(request("missing", Some(TOKEN))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn hashed_and_plaintext_tokens_match() {
+    let hashed = token::hash_token(TOKEN);
+    assert!(hashed.starts_with("sha256:"));
+    assert_ne!(hashed, token::hash_token(TOKEN), "hashes should be salted");
+    assert!(token::token_matches(&hashed, TOKEN));
+    assert!(!token::token_matches(&hashed, "wrong-token"));
+    assert!(!token::token_matches(TOKEN, TOKEN));
+
+    let identity = Identity::unregistered(TOKEN, b"key");
+    assert!(token::line_matches(&hashed, TOKEN, &identity));
+    assert!(!token::line_matches(&hashed, "wrong-token", &identity));
+    assert!(token::line_matches(TOKEN, TOKEN, &identity));
+    assert!(!token::line_matches(TOKEN, "wrong-token", &identity));
+    assert!(!token::line_matches("# test-token", TOKEN, &identity));
+}
+
+#[tokio::test]
+async fn migrate_tokens_hashes_plaintext_lines_only() {
+    let (dir, app) = setup("demo");
+    let readers = "# release team\n@release\ntoken:ci\ntest-token\n";
+    fs::write(dir.path().join("demo/readers.txt"), readers).unwrap();
+    fs::create_dir(dir.path().join("groups")).unwrap();
+    fs::write(dir.path().join("groups/release.txt"), "other-token\n").unwrap();
+    let store = open_store(&dir, None);
+
+    assert_eq!(store.migrate_tokens().unwrap(), 3);
+    assert_eq!(store.migrate_tokens().unwrap(), 0);
+
+    let migrated = fs::read_to_string(dir.path().join("demo/readers.txt")).unwrap();
+    let lines = migrated.lines().collect::<Vec<_>>();
+    assert_eq!(lines[..3], ["# release team", "@release", "token:ci"]);
+    assert!(token::token_matches(lines[3], TOKEN));
+    let group = fs::read_to_string(dir.path().join("groups/release.txt")).unwrap();
+    assert!(token::token_matches(group.trim(), "other-token"));
+
+    // both the direct and the group entry still grant access after migration
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/demo/versions"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let request = with_token(get_request("/project/demo/versions"), "other-token");
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+fn register_token(dir: &TempDir, name: &str, scopes: &[Scope], expires_at: Option<u64>) -> String {
+    let token = token::random_hex(32);
+    let record = TokenRecord {
+        name: name.to_owned(),
+        owner: None,
+        hash: token::hash_token(&token),
+        expires_at,
+        scopes: scopes.to_vec(),
+    };
+    open_store(dir, None).add_token(record).unwrap();
+    token
+}
+
+#[tokio::test]
+async fn registered_tokens_are_checked_for_expiry_and_scope() {
+    let (dir, app) = setup("demo");
+    let acl = format!("{}\ntoken:expired\ntoken:reader\n", TOKEN);
+    fs::write(dir.path().join("demo/readers.txt"), &acl).unwrap();
+    fs::write(dir.path().join("demo/writers.txt"), &acl).unwrap();
+    let expired = register_token(&dir, "expired", &[Scope::Read, Scope::Write], Some(1));
+    let reader = register_token(&dir, "reader", &[Scope::Read], None);
+
+    let request = with_token(get_request("/project/demo/versions"), &expired);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(response).await, "token_expired");
+
+    let request = with_token(get_request("/project/demo/versions"), &reader);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let request = with_token(
+        upload_request("demo", "1.0.0", &[("app.bin", b"app")]),
+        &reader,
+    );
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(response).await, "missing_scope");
+}
+
+#[tokio::test]
+async fn named_lines_only_match_registered_tokens() {
+    let (dir, app) = setup("demo");
+    fs::write(dir.path().join("demo/readers.txt"), "token:ci\n").unwrap();
+    let ci = register_token(&dir, "ci", &[Scope::Read], None);
+    let other = register_token(&dir, "other", &[Scope::Read], None);
+
+    let request = with_token(get_request("/project/demo/versions"), &ci);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    for token in [other.as_str(), "ci", "token:ci"] {
+        let request = with_token(get_request("/project/demo/versions"), token);
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{}", token);
+    }
+
+    let identity = Identity::unregistered("ci", b"key");
+    assert!(!token::line_matches("token:ci", "ci", &identity));
+}
+
+#[tokio::test]
+async fn groups_and_administrators_grant_access() {
+    let (dir, app) = setup("demo");
+    fs::create_dir(dir.path().join("other")).unwrap();
+    fs::write(
+        dir.path().join("demo/readers.txt"),
+        "@release\n@Bad/group\n",
+    )
+    .unwrap();
+    fs::create_dir(dir.path().join("groups")).unwrap();
+    fs::write(dir.path().join("groups/release.txt"), "group-token\n").unwrap();
+    fs::write(dir.path().join("admins.txt"), "admin-token\n").unwrap();
+    let scoped = register_token(&dir, "root", &[Scope::Admin], None);
+
+    let status = |project: &str, token: &str| {
+        let app = app.clone();
+        let request = with_token(
+            get_request(&format!("/project/{}/versions", project)),
+            token,
+        );
+        async move { app.oneshot(request).await.unwrap().status() }
+    };
+    assert_eq!(status("demo", "group-token").await, StatusCode::OK);
+    assert_eq!(status("other", "group-token").await, StatusCode::FORBIDDEN);
+    assert_eq!(status("demo", TOKEN).await, StatusCode::FORBIDDEN);
+    for token in ["admin-token", scoped.as_str()] {
+        for project in ["demo", "other"] {
+            assert_eq!(status(project, token).await, StatusCode::OK);
+        }
+        let request = with_token(
+            upload_request("other", "1.0.0", &[("app.bin", b"app")]),
+            token,
+        );
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let request = with_token(
+            Request::delete("/project/other/version/1.0.0")
+                .body(Body::empty())
+                .unwrap(),
+            token,
+        );
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+}
+
+#[tokio::test]
+async fn public_projects_are_readable_without_a_token() {
+    let (dir, app) = setup("demo");
+    let response = app
+        .clone()
+        .oneshot(upload_request("demo", "1.0.0", &[("app.bin", b"app")]))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    fs::write(dir.path().join("demo/project.json"), r#"{"public": true}"#).unwrap();
+    let anonymous = |request: Request<Body>| {
+        let mut request = request;
+        request.headers_mut().remove(header::AUTHORIZATION);
+        request
+    };
+
+    for uri in [
+        "/project/demo/versions",
+        "/project/demo/version/1.0.0",
+        "/project/demo/version/1.0.0/file/app.bin",
+    ] {
+        let response = app
+            .clone()
+            .oneshot(anonymous(get_request(uri)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", uri);
+    }
+    let request = anonymous(upload_request("demo", "1.1.0", &[("app.bin", b"app")]));
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let request = with_token(
+        upload_request("demo", "1.1.0", &[("app.bin", b"app")]),
+        "made-up",
+    );
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // a token that isn't listed still reads as its own identity rather than anonymously,
+    // so an expired one is rejected instead of quietly falling back
+    let request = with_token(get_request("/project/demo/versions"), "made-up");
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let expired = register_token(&dir, "expired", &[Scope::Read], Some(1));
+    let request = with_token(get_request("/project/demo/versions"), &expired);
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(error_code(response).await, "token_expired");
+}
+
+#[tokio::test]
+async fn administrators_manage_projects_and_acls() {
+    let (dir, app) = setup("demo");
+    fs::write(dir.path().join("admins.txt"), "admin-token\n").unwrap();
+    let admin_request = |method: &str, uri: &str, body: serde_json::Value| {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        with_token(request, "admin-token")
+    };
+
+    let new_project = serde_json::json!({"name": "app"});
+    let response = app
+        .clone()
+        .oneshot(admin_request(
+            "POST",
+            "/admin/projects",
+            new_project.clone(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = app
+        .clone()
+        .oneshot(admin_request(
+            "POST",
+            "/admin/projects",
+            new_project.clone(),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(error_code(response).await, "project_exists");
+
+    let response = app
+        .clone()
+        .oneshot(admin_request(
+            "POST",
+            "/admin/project/app/acl/readers",
+            serde_json::json!({"generate": true}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let added: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+    let generated = added["token"].as_str().unwrap().to_owned();
+    let readers = fs::read_to_string(dir.path().join("app/readers.txt")).unwrap();
+    assert!(
+        !readers.contains(&generated),
+        "tokens should be stored hashed"
+    );
+    let request = with_token(get_request("/project/app/versions"), &generated);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app
+        .clone()
+        .oneshot(admin_request(
+            "POST",
+            "/admin/project/app/acl/readers/revoke",
+            serde_json::json!({"token": generated}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_string(response).await, r#"{"removed":1}"#);
+    let request = with_token(get_request("/project/app/versions"), &generated);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // project members aren't administrators
+    for (method, uri, body) in [
+        (
+            "POST",
+            "/admin/projects",
+            serde_json::json!({"name": "other"}),
+        ),
+        ("GET", "/admin/project/demo/acl", serde_json::json!({})),
+        (
+            "POST",
+            "/admin/project/demo/acl/writers",
+            serde_json::json!({"generate": true}),
+        ),
+        ("DELETE", "/admin/project/demo", serde_json::json!({})),
+    ] {
+        let request = with_token(admin_request(method, uri, body), TOKEN);
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::FORBIDDEN,
+            "{} {}",
+            method,
+            uri
+        );
+        assert_eq!(error_code(response).await, "unauthorized_admin");
+    }
+    assert!(!dir.path().join("other").exists());
+    assert!(dir.path().join("demo").exists());
+}
+
+#[tokio::test]
+async fn project_listing_skips_what_it_cannot_read() {
+    let (dir, app) = setup("demo");
+    for version in ["nightly", "1.0.0", "1.1.0", "2.0.0"] {
+        let response = app
+            .clone()
+            .oneshot(upload_request("demo", version, &[("app.bin", b"app")]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let request = Request::post("/project/demo/version/2.0.0/yank")
+        .body(Body::empty())
+        .unwrap();
+    let response = app
+        .clone()
+        .oneshot(with_token(request, TOKEN))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    fs::write(dir.path().join("demo/versions/1.1.0/version.json"), "{").unwrap();
+    fs::create_dir(dir.path().join("broken")).unwrap();
+    fs::write(dir.path().join("broken/project.json"), "{public: true}").unwrap();
+
+    let response = app.clone().oneshot(get_request("/projects")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        body_string(response).await,
+        r#"[{"name":"demo","public":false,"versions":4,"latest":"1.0.0"}]"#
+    );
+}
+
+#[tokio::test]
+async fn versions_from_older_releases_are_still_served() {
+    let (dir, app) = setup("demo");
+    fs::write(dir.path().join("admins.txt"), format!("{}\n", TOKEN)).unwrap();
+    fs::create_dir(dir.path().join("other")).unwrap();
+    let version_dir = dir.path().join("demo/versions/0.1.0");
+    fs::create_dir_all(&version_dir).unwrap();
+    fs::write(version_dir.join("app.tar.gz"), "old release").unwrap();
+
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/demo/versions"))
+        .await
+        .unwrap();
+    assert_eq!(body_string(response).await, r#"["0.1.0"]"#);
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/demo/version/0.1.0/file/app.tar.gz"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_string(response).await, "old release");
+    assert!(version_dir.join("version.json").is_file());
+    let response = app
+        .clone()
+        .oneshot(upload_request("demo", "0.1.0", &[("app.tar.gz", b"new")]))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // promoting copies the file into a blob, which the old version then shares
+    let request = Request::post("/project/other/promote")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(r#"{"from": "demo", "version": "0.1.0"}"#))
+        .unwrap();
+    let response = app
+        .clone()
+        .oneshot(with_token(request, TOKEN))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/other/version/0.1.0/file/app.tar.gz"))
+        .await
+        .unwrap();
+    assert_eq!(body_string(response).await, "old release");
+
+    let request = Request::delete("/project/demo/version/0.1.0")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(with_token(request, TOKEN)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(!version_dir.exists());
+}
+
+#[tokio::test]
+async fn one_broken_project_does_not_stop_retention() {
+    let (dir, app) = setup("zzz");
+    for version in ["1.0.0", "1.1.0", "1.2.0"] {
+        let response = app
+            .clone()
+            .oneshot(upload_request("zzz", version, &[("app.bin", b"app")]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    fs::write(
+        dir.path().join("zzz/project.json"),
+        r#"{"retention": {"keep_last": 1}}"#,
+    )
+    .unwrap();
+    fs::create_dir(dir.path().join("aaa")).unwrap();
+    fs::write(dir.path().join("aaa/project.json"), "{retention").unwrap();
+
+    let store = open_store(&dir, None);
+    let expired = |project: &str, version: &str| (project.to_owned(), version.to_owned());
+    assert_eq!(
+        store.collect_garbage(true).unwrap(),
+        [expired("zzz", "1.0.0"), expired("zzz", "1.1.0")]
+    );
+    assert!(dir.path().join("zzz/versions/1.0.0").exists());
+    store.collect_garbage(false).unwrap();
+    let response = app
+        .oneshot(get_request("/project/zzz/versions"))
+        .await
+        .unwrap();
+    assert_eq!(body_string(response).await, r#"["1.2.0"]"#);
+}
+
+#[tokio::test]
+async fn versions_sort_by_semver_after_other_names() {
+    let (dir, app) = setup("demo");
+    for version in [
+        "1.10.0",
+        "nightly",
+        "2.0.0-rc.1",
+        "v1.2.0",
+        "1.9.0",
+        "snapshot",
+    ] {
+        let response = app
+            .clone()
+            .oneshot(upload_request("demo", version, &[("app.bin", b"app")]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK, "{}", version);
+    }
+    // upload times only have second precision, so backdate the one that should come first
+    let metadata_path = dir.path().join("demo/versions/snapshot/version.json");
+    let mut metadata: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&metadata_path).unwrap()).unwrap();
+    metadata["uploaded_at"] = 1.into();
+    fs::write(&metadata_path, metadata.to_string()).unwrap();
+
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/demo/versions"))
+        .await
+        .unwrap();
+    assert_eq!(
+        body_string(response).await,
+        r#"["snapshot","nightly","v1.2.0","1.9.0","1.10.0","2.0.0-rc.1"]"#
+    );
+    for (alias, expected) in [("latest", "2.0.0-rc.1"), ("latest-stable", "1.10.0")] {
+        let response = app
+            .clone()
+            .oneshot(get_request(&format!(
+                "/project/demo/version/{}/download",
+                alias
+            )))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            format!("/project/demo/version/{}/file/app.bin", expected)
+        );
+    }
+
+    // the project list picks the same latest version, even when it has to fall back to
+    // versions that aren't semantic versions
+    let response = app.clone().oneshot(get_request("/projects")).await.unwrap();
+    let projects: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+    assert_eq!(projects[0]["latest"], "2.0.0-rc.1");
+    for version in ["v1.2.0", "1.9.0", "1.10.0", "2.0.0-rc.1"] {
+        let request = Request::post(format!("/project/demo/version/{}/yank", version))
+            .body(Body::empty())
+            .unwrap();
+        let response = app
+            .clone()
+            .oneshot(with_token(request, TOKEN))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/demo/version/latest/download"))
+        .await
+        .unwrap();
+    assert_eq!(
+        response.headers()[header::LOCATION],
+        "/project/demo/version/nightly/file/app.bin"
+    );
+    let response = app.clone().oneshot(get_request("/projects")).await.unwrap();
+    let projects: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+    assert_eq!(projects[0]["latest"], "nightly");
+
+    for name in ["latest", "latest-stable"] {
+        let response = app
+            .clone()
+            .oneshot(upload_request("demo", name, &[("app.bin", b"app")]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(response).await, "invalid_version");
+    }
+}
+
+#[tokio::test]
+async fn yanked_versions_are_hidden_but_still_served() {
+    let (dir, app) = setup("demo");
+    for version in ["1.0.0", "1.1.0"] {
+        let response = app
+            .clone()
+            .oneshot(upload_request(
+                "demo",
+                version,
+                &[("app.bin", version.as_bytes())],
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let yank = |method: &str| {
+        let request = Request::builder()
+            .method(method)
+            .uri("/project/demo/version/1.1.0/yank?reason=broken")
+            .body(Body::empty())
+            .unwrap();
+        with_token(request, TOKEN)
+    };
+    let listed = |app: Router| async move {
+        let versions = app
+            .clone()
+            .oneshot(get_request("/project/demo/versions"))
+            .await
+            .unwrap();
+        let latest = app
+            .oneshot(get_request("/project/demo/version/latest/download"))
+            .await
+            .unwrap();
+        (
+            body_string(versions).await,
+            latest.headers()[header::LOCATION]
+                .to_str()
+                .unwrap()
+                .to_owned(),
+        )
+    };
+
+    let response = app.clone().oneshot(yank("POST")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let metadata: serde_json::Value = serde_json::from_str(&body_string(response).await).unwrap();
+    assert_eq!(metadata["yanked"]["reason"], "broken");
+    assert_eq!(
+        listed(app.clone()).await,
+        (
+            r#"["1.0.0"]"#.to_owned(),
+            "/project/demo/version/1.0.0/file/app.bin".to_owned()
+        )
+    );
+    let response = app
+        .clone()
+        .oneshot(get_request("/project/demo/version/1.1.0/file/app.bin"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_string(response).await, "1.1.0");
+
+    let response = app.clone().oneshot(yank("DELETE")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        listed(app.clone()).await,
+        (
+            r#"["1.0.0","1.1.0"]"#.to_owned(),
+            "/project/demo/version/1.1.0/file/app.bin".to_owned()
+        )
+    );
+
+    // writing doesn't include deleting, which needs the delete scope or an administrator
+    let delete = || {
+        let request = Request::delete("/project/demo/version/1.1.0")
+            .body(Body::empty())
+            .unwrap();
+        with_token(request, TOKEN)
+    };
+    let response = app.clone().oneshot(delete()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(error_code(response).await, "missing_scope");
+    fs::write(dir.path().join("demo/writers.txt"), "token:deleter\n").unwrap();
+    let deleter = register_token(&dir, "deleter", &[Scope::Read, Scope::Delete], None);
+    let request = with_token(delete(), &deleter);
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = app
+        .oneshot(get_request("/project/demo/versions"))
+        .await
+        .unwrap();
+    assert_eq!(body_string(response).await, r#"["1.0.0"]"#);
+}
+
+#[tokio::test]
+async fn uploads_are_checked_against_given_checksums() {
+    let (dir, app) = setup("demo");
+    const SHA256: &str = "a172cedcae47474b615c54d510a5d84a8dea3032e958587430b413538be3f333";
+    let upload = |version: &str, query: &str| {
+        let mut request = upload_request("demo", version, &[("app.bin", b"app")]);
+        *request.uri_mut() = format!("/project/demo/upload?version={}&{}", version, query)
+            .parse()
+            .unwrap();
+        request
+    };
+
+    for query in [
+        format!("sha256.app.bin={}", SHA256.replace('a', "b")),
+        format!("sha256.app.bin={}&sha256.other.bin={}", SHA256, SHA256),
+    ] {
+        let response = app.clone().oneshot(upload("1.0.0", &query)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", query);
+        assert_eq!(error_code(response).await, "checksum_mismatch");
+    }
+    // the rejected uploads' staging directories are removed in the background
+    for _ in 0..100 {
+        if fs::read_dir(dir.path().join(".tmp"))
+            .unwrap()
+            .next()
+            .is_none()
+        {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert!(fs::read_dir(dir.path().join(".tmp"))
+        .unwrap()
+        .next()
+        .is_none());
+    assert!(!dir.path().join("demo/versions/1.0.0").exists());
+
+    let query = format!("sha256.app.bin={}", SHA256.to_ascii_uppercase());
+    let response = app.clone().oneshot(upload("1.0.0", &query)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .oneshot(get_request("/project/demo/version/1.0.0/file/app.bin"))
+        .await
+        .unwrap();
+    assert_eq!(
+        response.headers()["digest"],
+        "sha-256=oXLO3K5HR0thXFTVEKXYSo3qMDLpWFh0MLQTU4vj8zM="
+    );
+    assert_eq!(response.headers()[header::ETAG], format!("\"{}\"", SHA256));
+}
+
+#[test]
+fn parse_sizes_and_durations() {
+    for (s, expected) in [
+        ("0", 0),
+        ("512", 512),
+        ("10K", 10 << 10),
+        ("10k", 10 << 10),
+        ("2M", 2 << 20),
+        ("1G", 1 << 30),
+        ("3t", 3 << 40),
+    ] {
+        assert_eq!(parse_size(s), Ok(expected), "{}", s);
+    }
+    for s in ["", "M", "1.5G", "-1", "10P", "99999999T"] {
+        assert!(parse_size(s).is_err(), "{}", s);
+    }
+
+    for (s, expected) in [
+        ("0", 0),
+        ("30", 30),
+        ("30s", 30),
+        ("5m", 5 * 60),
+        ("12h", 12 * 60 * 60),
+        ("7d", 7 * 24 * 60 * 60),
+    ] {
+        assert_eq!(
+            parse_duration(s),
+            Ok(std::time::Duration::from_secs(expected)),
+            "{}",
+            s
+        );
+    }
+    for s in ["", "h", "1.5h", "-1s", "1w", "999999999999999999d"] {
+        assert!(parse_duration(s).is_err(), "{}", s);
+    }
+}
+
+#[tokio::test]
+async fn unregistered_tokens_are_named_with_a_server_secret() {
+    let (dir, app) = setup("demo");
+    let uploader = |app: Router, version: &'static str| async move {
+        let response = app
+            .clone()
+            .oneshot(upload_request("demo", version, &[("app.bin", b"app")]))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app
+            .oneshot(get_request(&format!("/project/demo/version/{}", version)))
+            .await
+            .unwrap();
+        let metadata: serde_json::Value =
+            serde_json::from_str(&body_string(response).await).unwrap();
+        metadata["uploader"].as_str().unwrap().to_owned()
+    };
+    let first = uploader(app, "1.0.0").await;
+
+    let key = fs::read(dir.path().join("fingerprint.key")).unwrap();
+    assert_eq!(first, Identity::unregistered(TOKEN, &key).name());
+    assert_ne!(first, Identity::unregistered(TOKEN, b"other key").name());
+    // the bare hash of the token would let anyone who sees the name check guesses against it
+    let bare = format!("{:x}", sha2::Sha256::digest(TOKEN.as_bytes()));
+    assert!(!bare.starts_with(first.trim_start_matches("unregistered-")));
+
+    // the secret is kept, so names stay the same across restarts
+    let app = router(Arc::new(open_store(&dir, None)));
+    assert_eq!(uploader(app, "1.1.0").await, first);
+}
+
+#[tokio::test]
+async fn projects_with_uploads_in_progress_are_not_deleted() {
+    let (dir, app) = setup("demo");
+    fs::write(dir.path().join("admins.txt"), format!("{}\n", TOKEN)).unwrap();
+    let delete_project = || {
+        let request = Request::delete("/admin/project/demo")
+            .body(Body::empty())
+            .unwrap();
+        with_token(request, TOKEN)
+    };
+
+    // an upload whose body arrives only when the test sends it
+    let (sender, receiver) = tokio::sync::mpsc::channel::<Bytes>(1);
+    let chunks = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let chunk = receiver.recv().await?;
+        Some((Ok::<_, std::io::Error>(chunk), receiver))
+    });
+    let mut request = upload_request("demo", "1.0.0", &[]);
+    *request.body_mut() = Body::from_stream(chunks);
+    let upload = tokio::spawn(app.clone().oneshot(request));
+    let body = multipart_body(&[("app.bin", b"app")]);
+    let (start, rest) = body.split_at(body.len() / 2);
+    sender.send(Bytes::copy_from_slice(start)).await.unwrap();
+    while fs::read_dir(dir.path().join(".tmp")).map_or(true, |mut entries| entries.next().is_none())
+    {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    let response = app.clone().oneshot(delete_project()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(error_code(response).await, "project_busy");
+
+    sender.send(Bytes::copy_from_slice(rest)).await.unwrap();
+    drop(sender);
+    assert_eq!(upload.await.unwrap().unwrap().status(), StatusCode::OK);
+    let response = app.clone().oneshot(delete_project()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert!(!dir.path().join("demo").exists());
+}
@@ -0,0 +1,225 @@
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::metadata::sha256_hex;
+
+const HASH_PREFIX: &str = "sha256:";
+const NAMED_TOKEN_PREFIX: &str = "token:";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Delete,
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::Delete => "delete",
+            Scope::Admin => "admin",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "delete" => Ok(Scope::Delete),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!("unknown scope {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenRecord {
+    pub name: String,
+    #[serde(default)]
+    pub owner: Option<String>,
+    pub hash: String,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Identity {
+    name: String,
+    registered: bool,
+    scopes: Vec<Scope>,
+}
+
+impl Identity {
+    pub fn registered(record: &TokenRecord) -> Self {
+        Identity {
+            name: record.name.clone(),
+            registered: true,
+            scopes: record.scopes.clone(),
+        }
+    }
+
+    // tokens that only appear in project ACLs can read and write wherever they're listed.
+    // They're named by a hash keyed with a server secret, since the name ends up in metadata
+    // anyone who can read the project sees, and plaintext tokens may be easy to guess
+    pub fn unregistered(token: &str, fingerprint_key: &[u8]) -> Self {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(fingerprint_key).expect("HMAC takes keys of any size");
+        mac.update(token.as_bytes());
+        let fingerprint = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .take(6)
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        Identity {
+            name: format!("unregistered-{}", fingerprint),
+            registered: false,
+            scopes: vec![Scope::Read, Scope::Write],
+        }
+    }
+
+    pub fn anonymous() -> Self {
+        Identity {
+            name: "anonymous".to_owned(),
+            registered: false,
+            scopes: vec![Scope::Read],
+        }
+    }
+
+    // the server acting on its own, e.g. when enforcing retention policies
+    pub fn internal(name: &str) -> Self {
+        Identity {
+            name: name.to_owned(),
+            registered: false,
+            scopes: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() | ['-', '_'].contains(&c))
+}
+
+pub fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0; bytes];
+    getrandom::fill(&mut buf).expect("failed to get randomness from the operating system");
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn salted_hash(salt: &str, token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(token.as_bytes());
+    sha256_hex(hasher)
+}
+
+pub fn hash_token(token: &str) -> String {
+    let salt = random_hex(16);
+    format!("{}{}:{}", HASH_PREFIX, salt, salted_hash(&salt, token))
+}
+
+// group references (`@group`) are resolved by the store, never compared as tokens
+fn is_token_line(line: &str) -> bool {
+    !line.is_empty() && !line.starts_with('#') && !line.starts_with('@')
+}
+
+pub fn is_plaintext(line: &str) -> bool {
+    is_token_line(line) && !line.starts_with(HASH_PREFIX) && !line.starts_with(NAMED_TOKEN_PREFIX)
+}
+
+pub fn token_matches(hashed: &str, token: &str) -> bool {
+    match hashed
+        .strip_prefix(HASH_PREFIX)
+        .and_then(|rest| rest.split_once(':'))
+    {
+        Some((salt, hash)) => salted_hash(salt, token)
+            .as_bytes()
+            .ct_eq(hash.as_bytes())
+            .into(),
+        None => false,
+    }
+}
+
+pub fn line_matches(line: &str, token: &str, identity: &Identity) -> bool {
+    if !is_token_line(line) {
+        return false;
+    }
+    if let Some(name) = line.strip_prefix(NAMED_TOKEN_PREFIX) {
+        return identity.registered && identity.name == name;
+    }
+    match line.strip_prefix(HASH_PREFIX) {
+        Some(_) => token_matches(line, token),
+        // tokens written before hashing was introduced are still accepted
+        None => line.as_bytes().ct_eq(token.as_bytes()).into(),
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct AclEntry {
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+impl AclEntry {
+    pub fn line(&self) -> Option<String> {
+        match (&self.token, &self.name, &self.group) {
+            (Some(token), None, None) if !token.is_empty() => Some(hash_token(token)),
+            (None, Some(name), None) if valid_name(name) => {
+                Some(format!("{}{}", NAMED_TOKEN_PREFIX, name))
+            }
+            (None, None, Some(group)) if valid_name(group) => Some(format!("@{}", group)),
+            _ => None,
+        }
+    }
+
+    // how the entry appears in logs, naming tokens the same way as their identity
+    pub fn describe(&self, fingerprint_key: &[u8]) -> String {
+        match (&self.token, &self.name, &self.group) {
+            (Some(token), _, _) => Identity::unregistered(token, fingerprint_key).name,
+            (_, Some(name), _) => format!("{}{}", NAMED_TOKEN_PREFIX, name),
+            (_, _, Some(group)) => format!("@{}", group),
+            _ => "nothing".to_owned(),
+        }
+    }
+
+    pub fn matches(&self, line: &str) -> bool {
+        match (&self.token, &self.name, &self.group) {
+            (Some(token), None, None) => {
+                token_matches(line, token) || line.as_bytes().ct_eq(token.as_bytes()).into()
+            }
+            (None, Some(name), None) => line.strip_prefix(NAMED_TOKEN_PREFIX) == Some(name),
+            (None, None, Some(group)) => line.strip_prefix('@') == Some(group),
+            _ => false,
+        }
+    }
+}
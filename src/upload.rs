@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::extract::multipart::Field;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::{event, Level};
+
+use crate::metadata::*;
+use crate::store::*;
+
+const MAX_METADATA_SIZE: usize = 64 * 1024;
+
+pub struct Upload {
+    store: Arc<Store>,
+    project: ProjectWriter,
+    version: Version,
+    _reservation: VersionReservation,
+    staging_dir: Option<PathBuf>,
+    metadata: VersionMetadata,
+    expected_checksums: HashMap<String, String>,
+    received: u64,
+}
+
+impl Upload {
+    pub async fn new(
+        store: Arc<Store>,
+        project: ProjectWriter,
+        version: Version,
+    ) -> Result<Self, StoreError> {
+        let reservation = store.reserve_version(&project, &version)?;
+        let (project, version, staging_dir) = store
+            .run_blocking(move |store| {
+                if store.version_exists(project.reader(), &version) {
+                    return Err(StoreError::VersionExists);
+                }
+                let staging_dir = store.create_staging_dir()?;
+                Ok((project, version, staging_dir))
+            })
+            .await?;
+        let uploader = project.identity().name().to_owned();
+        Ok(Upload {
+            store,
+            project,
+            version,
+            _reservation: reservation,
+            staging_dir: Some(staging_dir),
+            metadata: VersionMetadata {
+                uploader: Some(uploader),
+                ..Default::default()
+            },
+            expected_checksums: HashMap::new(),
+            received: 0,
+        })
+    }
+
+    fn staging_dir(&self) -> &Path {
+        self.staging_dir.as_ref().unwrap()
+    }
+
+    pub fn set_commit(&mut self, commit: String) {
+        self.metadata.commit = Some(commit);
+    }
+
+    pub fn set_primary(&mut self, file_name: String) {
+        self.metadata.primary = Some(file_name);
+    }
+
+    pub fn add_label(&mut self, key: String, value: String) {
+        self.metadata.labels.insert(key, value);
+    }
+
+    // the metadata part is held in memory, so it gets a small cap of its own on top of
+    // counting toward the upload size
+    pub async fn read_metadata(&mut self, mut field: Field<'_>) -> Result<(), StoreError> {
+        let mut contents = Vec::new();
+        while let Some(chunk) = field.chunk().await? {
+            self.count_received(chunk.len())?;
+            if contents.len() + chunk.len() > MAX_METADATA_SIZE {
+                return Err(StoreError::InvalidMetadata(format!(
+                    "metadata part exceeds {} bytes",
+                    MAX_METADATA_SIZE
+                )));
+            }
+            contents.extend_from_slice(&chunk);
+        }
+        let metadata = serde_json::from_slice(&contents)
+            .map_err(|e| StoreError::InvalidMetadata(e.to_string()))?;
+        self.add_metadata(metadata);
+        Ok(())
+    }
+
+    fn add_metadata(&mut self, metadata: UploadMetadata) {
+        if let Some(commit) = metadata.commit {
+            self.set_commit(commit);
+        }
+        if let Some(primary) = metadata.primary {
+            self.set_primary(primary);
+        }
+        self.metadata.labels.extend(metadata.labels);
+    }
+
+    pub fn expect_checksum(&mut self, file_name: String, sha256: String) {
+        self.expected_checksums
+            .insert(file_name, sha256.to_ascii_lowercase());
+    }
+
+    pub async fn add_file(
+        &mut self,
+        file_name: String,
+        field: Field<'_>,
+    ) -> Result<(), StoreError> {
+        validate_file_name(&file_name)?;
+        if self.metadata.file(&file_name).is_some() {
+            return Err(StoreError::DuplicateFile(file_name));
+        }
+        let mut path = self.staging_dir().to_path_buf();
+        path.push("files");
+        path.push(&file_name);
+        let content_type = field.content_type().map(|t| t.to_owned());
+        let (size, sha256) = self.stream_to(&path, field).await?;
+        if let Some(expected) = self.expected_checksums.get(&file_name) {
+            if *expected != sha256 {
+                return Err(StoreError::ChecksumMismatch(file_name));
+            }
+        }
+        self.metadata.files.push(FileMetadata {
+            name: file_name,
+            size,
+            content_type,
+            sha256,
+        });
+        Ok(())
+    }
+
+    async fn stream_to(
+        &mut self,
+        path: &Path,
+        mut field: Field<'_>,
+    ) -> Result<(u64, String), StoreError> {
+        let mut file = fs::File::create_new(path).await.map_err(StoreError::IO)?;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        while let Some(chunk) = field.chunk().await? {
+            size += chunk.len() as u64;
+            self.count_received(chunk.len())?;
+            hasher.update(&chunk);
+            file.write_all(&chunk).await.map_err(StoreError::IO)?;
+        }
+        file.flush().await.map_err(StoreError::IO)?;
+        Ok((size, sha256_hex(hasher)))
+    }
+
+    fn count_received(&mut self, len: usize) -> Result<(), StoreError> {
+        self.received += len as u64;
+        match self.store.max_upload_size() {
+            Some(limit) if self.received > limit => Err(StoreError::PayloadTooLarge(limit)),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn commit(self) -> Result<VersionMetadata, StoreError> {
+        let store = self.store.clone();
+        store.run_blocking(move |_| self.publish()).await
+    }
+
+    fn publish(mut self) -> Result<VersionMetadata, StoreError> {
+        if self.metadata.files.is_empty() {
+            return Err(StoreError::NoFiles);
+        }
+        if let Some(missing) = self
+            .expected_checksums
+            .keys()
+            .find(|name| self.metadata.file(name).is_none())
+        {
+            return Err(StoreError::ChecksumMismatch(missing.clone()));
+        }
+        if let Some(primary) = &self.metadata.primary {
+            if self.metadata.file(primary).is_none() {
+                return Err(StoreError::InvalidMetadata(format!(
+                    "primary file {} was not uploaded",
+                    primary
+                )));
+            }
+        }
+        let mut metadata = std::mem::take(&mut self.metadata);
+        metadata.uploaded_at = unix_time(SystemTime::now());
+        self.store
+            .publish_version(&self.project, &self.version, self.staging_dir(), &metadata)?;
+        self.staging_dir = None;
+        event!(
+            Level::INFO,
+            "{} uploaded version {} for project {} with files {:?}",
+            self.project.identity().name(),
+            self.version.name(),
+            self.project.name(),
+            metadata.files.iter().map(|f| &f.name).collect::<Vec<_>>()
+        );
+        Ok(metadata)
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if let Some(staging_dir) = self.staging_dir.take() {
+            tokio::task::spawn_blocking(move || std::fs::remove_dir_all(staging_dir));
+        }
+    }
+}